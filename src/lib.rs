@@ -0,0 +1,50 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Pingkeeper monitorizes that network is reachable and, in case of failure, runs a command.
+//!
+//! It can be used from the command line or embedded as a library:
+//!
+//! ```no_run
+//! use pingkeeper::Pingkeeper;
+//! use std::thread;
+//! use std::time::Duration;
+//!
+//! let pk = Pingkeeper::new("openvpn client.ovpn")
+//!     .hosts(vec![String::from("1.1.1.1"), String::from("8.8.8.8")])
+//!     .keep_alive(true)
+//!     .network_every(Duration::from_secs(10));
+//! let stop = pk.stop_handle();
+//! let supervisor = thread::spawn(move || pk.run());
+//! // ...
+//! stop.stop();
+//! supervisor.join().unwrap().unwrap();
+//! ```
+
+mod pingkeeper;
+pub use self::pingkeeper::{
+    pingkeeper, Adoption, Cgroup, ChangeKind, CheckExpr, CheckLeaf, Command, CommandProbe,
+    Credentials, CredentialsError, Escalation, EscalationAction, EscalationStep, Event, Executor,
+    ExecutorError, ExitDecision, ExitPolicy, FailureLayer, GroupAction, GroupSpec, HostGroup,
+    HttpProbe, InterfaceProbe, LeafKind, Limits, Liveness, LivenessCheck, LivenessMonitor,
+    LogLevel, Metric, NetlinkListener, NetworkChange, NetworkError, NetworkMonitor, NetworkState,
+    Opt, OutputLine, OutputRule, PingProbe, PingStats, Pingkeeper, PingkeeperError, Probe,
+    ProbeOutcome, Quality, QualityThresholds, Readiness, ReadinessCheck, ReadinessMonitor,
+    ReadyState, RestartPolicy, RuleAction, RuleMatch, RuleMonitor, Signal, Source, SpawnReason,
+    StopHandle, Stream, Subscriber, TcpProbe, Thresholds, Usage, UsageMonitor,
+};
@@ -18,7 +18,6 @@
 
 use structopt::StructOpt;
 
-mod pingkeeper;
 use pingkeeper::{pingkeeper, Opt, PingkeeperError};
 
 fn main() -> Result<(), PingkeeperError> {
@@ -16,20 +16,78 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+pub use nix::sys::signal::Signal;
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::Arc;
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 mod opt;
 pub use opt::Opt;
 
+mod command;
+pub use command::Command;
+
+mod limits;
+pub use limits::{Cgroup, Limits};
+
+mod credentials;
+use credentials::drop_privileges;
+pub use credentials::{Credentials, CredentialsError};
+
+mod adopt;
+pub use adopt::Adoption;
+use adopt::{remove_pidfile, write_pidfile};
+
 mod executor;
-use executor::Executor;
+pub use executor::{Executor, ExecutorError, SpawnReason};
 
 mod network_monitor;
-use network_monitor::NetworkMonitor;
+pub use network_monitor::{
+    CommandProbe, FailureLayer, HttpProbe, InterfaceProbe, NetworkError, NetworkMonitor, PingProbe,
+    PingStats, Probe, ProbeOutcome, Quality, QualityThresholds, Source, TcpProbe,
+};
+
+mod usage;
+pub use usage::{Metric, Thresholds, Usage, UsageMonitor};
+
+mod liveness;
+pub use liveness::{Liveness, LivenessCheck, LivenessMonitor};
+
+mod output;
+pub use output::{OutputLine, Stream};
+
+mod readiness;
+pub use readiness::{Readiness, ReadinessCheck, ReadinessMonitor, ReadyState};
+
+mod rules;
+pub use rules::{OutputRule, RuleAction, RuleMatch, RuleMonitor};
+
+mod expression;
+pub use expression::{CheckExpr, CheckLeaf, LeafKind};
+
+mod escalation;
+pub use escalation::{Escalation, EscalationAction, EscalationStep};
+
+mod groups;
+pub use groups::{GroupAction, GroupSpec, HostGroup};
+
+mod policy;
+pub use policy::{ExitDecision, ExitPolicy, RestartPolicy};
+
+mod netlink;
+pub use netlink::{ChangeKind, NetlinkListener, NetworkChange};
+
+mod events;
+pub use events::{Event, NetworkState, Subscriber};
 
 mod logger;
-use logger::{logger, LogLevel};
+use logger::event_logger;
+pub use logger::LogLevel;
 
 // ---------------------- Spawn ----------------------
 /// Spawn errors
@@ -47,13 +105,14 @@ enum SpawnOk {
 
 fn spawn_controller(
     executor: &mut Executor,
-    kill_cmd: &Option<String>,
+    kill_cmd: &Option<Command>,
     quiet: bool,
+    reason: SpawnReason,
 ) -> Result<SpawnOk, SpawnErr> {
     // If previous child pid, kill
     if let Some(pid) = executor.get_pid() {
         if let Some(cmd) = kill_cmd {
-            if executor.kill_custom_cmd(cmd).is_ok() {
+            if executor.kill_custom_cmd(cmd.clone()).is_ok() {
                 Ok(SpawnOk::KillOk(pid))
             } else {
                 Err(SpawnErr::KillErr(pid))
@@ -64,7 +123,7 @@ fn spawn_controller(
             Err(SpawnErr::KillErr(pid))
         }
     } else {
-        executor.spawn(quiet);
+        executor.spawn(quiet, reason);
         if let Some(pid) = executor.get_pid() {
             Ok(SpawnOk::SpawnOk(pid))
         } else {
@@ -73,6 +132,43 @@ fn spawn_controller(
     }
 }
 
+/// Checks output lines against the rules, running hooks and emitting matches
+///
+/// Returns the restart reason, if a rule restarts, and whether a rule kills.
+fn check_rules(
+    rules: &mut RuleMonitor,
+    lines: &[OutputLine],
+    pid: u32,
+    hook: &Option<Command>,
+    executor: &mut Executor,
+    emit: &dyn Fn(Event),
+) -> (Option<SpawnReason>, bool) {
+    let mut restart = None;
+    let mut kill_child = false;
+    for found in rules.check(lines) {
+        match found.action {
+            RuleAction::Restart => restart = restart.or(Some(SpawnReason::OutputMatched)),
+            RuleAction::Kill => kill_child = true,
+            RuleAction::Hook => {
+                if let Some(cmd) = hook {
+                    executor.run_hook(
+                        cmd.clone(),
+                        &[("__PK_RULE", &found.rule), ("__PK_MATCH", &found.line)],
+                    );
+                }
+            }
+            RuleAction::Warn => (),
+        }
+        emit(Event::OutputMatched {
+            pid,
+            rule: found.rule,
+            action: found.action,
+            line: found.line,
+        });
+    }
+    (restart, kill_child)
+}
+
 // ---------------------- Pingkeeper ----------------------
 
 /// Pingkeeper errors
@@ -81,143 +177,1470 @@ pub enum PingkeeperError {
     NoHostsToPing,
     TooManyErrors,
     InvalidTimeout,
+    NoCommand,
+    InvalidEnvFile,
+    UnknownUser,
+    UnknownGroup,
+    UnknownCapability,
+    CannotDropPrivileges,
+    CannotCreateCgroup,
+    CannotCreateNotifySocket,
+    NoOutputHook,
+    FatalExitStatus,
+    CannotListenNetlink,
+}
+impl From<CredentialsError> for PingkeeperError {
+    fn from(err: CredentialsError) -> Self {
+        match err {
+            CredentialsError::UnknownUser => PingkeeperError::UnknownUser,
+            CredentialsError::UnknownGroup => PingkeeperError::UnknownGroup,
+            CredentialsError::UnknownCapability => PingkeeperError::UnknownCapability,
+            CredentialsError::NotPermitted => PingkeeperError::CannotDropPrivileges,
+        }
+    }
 }
 
 /// Time between loops
 const CHECK_MS: usize = 100;
 
+/// Handle to stop a running Pingkeeper from another thread
+#[derive(Debug, Clone)]
+pub struct StopHandle(Arc<AtomicBool>);
+
+impl StopHandle {
+    /// Asks Pingkeeper to kill the child process, if any, and return
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+    /// Has stop been requested?
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
 /// Monitorizes that network is reachable and, in case of failure, runs a command
-pub fn pingkeeper(opt: Opt) -> Result<(), PingkeeperError> {
-    // logger
-    let logger = if !opt.quiet {
+///
+/// Built with chained setters, then started with `run`.
+pub struct Pingkeeper {
+    command: Command,
+    hosts: Vec<String>,
+    port: u16,
+    timeout: Duration,
+    use_ping: bool,
+    ping_opt: String,
+    check_cmd: Option<Command>,
+    check_timeout: Option<Duration>,
+    probe: Option<Arc<dyn Probe>>,
+    check_expr: Option<CheckExpr>,
+    interfaces: Vec<String>,
+    interface_probe: InterfaceProbe,
+    source: Source,
+    host_groups: Vec<HostGroup>,
+    quality: QualityThresholds,
+    samples: usize,
+    degraded_cmd: Option<Command>,
+    degraded_is_down: bool,
+    diagnose: bool,
+    layer_cmds: Vec<(FailureLayer, Command)>,
+    kill_cmd: Option<Command>,
+    keep_alive: bool,
+    gate: bool,
+    edge: bool,
+    recovery_cmd: Option<Command>,
+    repeat_every: Option<Duration>,
+    netlink: Option<Duration>,
+    wait_after_exec: Duration,
+    network_every: Duration,
+    signal: Option<Signal>,
+    current_dir: Option<PathBuf>,
+    env_clear: bool,
+    env_remove: Vec<String>,
+    env_file: Option<PathBuf>,
+    env: Vec<(String, String)>,
+    user: Option<String>,
+    group: Option<String>,
+    groups: Option<Vec<String>>,
+    hook_user: Option<String>,
+    drop_privileges: Option<String>,
+    keep_caps: Vec<String>,
+    limits: Limits,
+    cgroup: Option<PathBuf>,
+    memory_max: Option<u64>,
+    cpu_max: Option<u32>,
+    thresholds: Thresholds,
+    usage_every: Duration,
+    exceeded_for: Duration,
+    liveness: Option<Liveness>,
+    readiness: Option<Readiness>,
+    output_rules: Vec<OutputRule>,
+    output_hook: Option<Command>,
+    output_cooldown: Duration,
+    exit_policy: ExitPolicy,
+    adoption: Adoption,
+    escalation_steps: Vec<EscalationStep>,
+    stable_for: Duration,
+    max_errors: usize,
+    log_level: LogLevel,
+    quiet: bool,
+    subscribers: Vec<Subscriber>,
+    stop: StopHandle,
+}
+
+// Builder
+impl Pingkeeper {
+    /// Creates a new Pingkeeper for a command, with the same defaults as the command line
+    ///
+    /// A string is run through `/bin/sh -c`, a vector of program and arguments is executed directly.
+    pub fn new<C: Into<Command>>(command: C) -> Self {
+        Pingkeeper {
+            command: command.into(),
+            hosts: ["8.8.8.8", "8.8.6.6", "1.1.1.1", "1.0.0.1"]
+                .iter()
+                .map(|h| h.to_string())
+                .collect(),
+            port: 53,
+            timeout: Duration::from_secs(2),
+            use_ping: false,
+            ping_opt: String::from("-c1"),
+            check_cmd: None,
+            check_timeout: None,
+            probe: None,
+            check_expr: None,
+            interfaces: vec![],
+            interface_probe: InterfaceProbe::new(),
+            source: Source::default(),
+            host_groups: vec![],
+            quality: QualityThresholds::default(),
+            samples: 5,
+            degraded_cmd: None,
+            degraded_is_down: false,
+            diagnose: false,
+            layer_cmds: vec![],
+            kill_cmd: None,
+            keep_alive: false,
+            gate: false,
+            edge: false,
+            recovery_cmd: None,
+            repeat_every: None,
+            netlink: None,
+            wait_after_exec: Duration::from_secs(5),
+            network_every: Duration::from_secs(5),
+            signal: Some(Signal::SIGINT),
+            current_dir: None,
+            env_clear: false,
+            env_remove: Vec::new(),
+            env_file: None,
+            env: Vec::new(),
+            user: None,
+            group: None,
+            groups: None,
+            hook_user: None,
+            drop_privileges: None,
+            keep_caps: Vec::new(),
+            limits: Limits::default(),
+            cgroup: None,
+            memory_max: None,
+            cpu_max: None,
+            thresholds: Thresholds::default(),
+            usage_every: Duration::from_secs(5),
+            exceeded_for: Duration::from_secs(0),
+            liveness: None,
+            readiness: None,
+            output_rules: Vec::new(),
+            output_hook: None,
+            output_cooldown: Duration::from_secs(60),
+            exit_policy: ExitPolicy::default(),
+            adoption: Adoption::default(),
+            escalation_steps: vec![],
+            stable_for: Duration::from_secs(300),
+            max_errors: 0,
+            log_level: LogLevel::ERROR,
+            quiet: false,
+            subscribers: Vec::new(),
+            stop: StopHandle(Arc::new(AtomicBool::new(false))),
+        }
+    }
+    /// Sets addresses or hosts to check
+    pub fn hosts(mut self, hosts: Vec<String>) -> Self {
+        self.hosts = hosts;
+        self
+    }
+    /// Sets default port for hosts without port
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+    /// Sets timeout for direct connection
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Uses system's `ping` to check network
+    pub fn use_ping(mut self, use_ping: bool) -> Self {
+        self.use_ping = use_ping;
+        self
+    }
+    /// Sets options for `ping` command
+    pub fn ping_opt<S: Into<String>>(mut self, ping_opt: S) -> Self {
+        self.ping_opt = ping_opt.into();
+        self
+    }
+    /// Sets a custom command to check
+    pub fn check_cmd<C: Into<Command>>(mut self, check_cmd: Option<C>) -> Self {
+        self.check_cmd = check_cmd.map(Into::into);
+        self
+    }
+    /// Sets timeout of check commands, they are waited for without one
+    pub fn check_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.check_timeout = timeout;
+        self
+    }
+    /// Sets a custom probe to check network, overrides built-in probes
+    pub fn probe<P: Probe + 'static>(mut self, probe: P) -> Self {
+        self.probe = Some(Arc::new(probe));
+        self
+    }
+    /// Sets a boolean expression of checks, overrides the network probe and hosts, checked after interfaces
+    pub fn check_expr(mut self, expr: Option<CheckExpr>) -> Self {
+        self.check_expr = expr;
+        self
+    }
+    /// Adds a network interface to check with the interface probe before probing hosts
+    pub fn interface<S: Into<String>>(mut self, name: S) -> Self {
+        self.interfaces.push(name.into());
+        self
+    }
+    /// Sets the probe for network interfaces
+    pub fn interface_probe(mut self, probe: InterfaceProbe) -> Self {
+        self.interface_probe = probe;
+        self
+    }
+    /// Sets source address or interface of built-in tcp and ping probes
+    pub fn source(mut self, source: Source) -> Self {
+        self.source = source;
+        self
+    }
+    /// Adds a host group, checked along with network
+    pub fn host_group(mut self, group: HostGroup) -> Self {
+        self.host_groups.push(group);
+        self
+    }
+    /// Sets latency and loss thresholds of a degraded network
+    pub fn quality(mut self, thresholds: QualityThresholds) -> Self {
+        self.quality = thresholds;
+        self
+    }
+    /// Sets probes to measure quality of network
+    pub fn samples(mut self, samples: usize) -> Self {
+        self.samples = samples.max(1);
+        self
+    }
+    /// Sets a command to run when network becomes degraded
+    ///
+    /// It gets `__PK_P50_MS`, `__PK_P95_MS` and `__PK_LOSS_PERCENT`.
+    pub fn degraded_cmd<C: Into<Command>>(mut self, cmd: Option<C>) -> Self {
+        self.degraded_cmd = cmd.map(Into::into);
+        self
+    }
+    /// Treats a degraded network as unreachable
+    pub fn degraded_is_down(mut self, degraded_is_down: bool) -> Self {
+        self.degraded_is_down = degraded_is_down;
+        self
+    }
+    /// Diagnoses the layer of failed checks, command and hooks get `__PK_FAILURE_LAYER`
+    pub fn diagnose(mut self, diagnose: bool) -> Self {
+        self.diagnose = diagnose;
+        self
+    }
+    /// Sets a command to run instead of command when a check fails at this layer
+    ///
+    /// Requires `diagnose`.
+    pub fn layer_cmd<C: Into<Command>>(mut self, layer: FailureLayer, cmd: C) -> Self {
+        self.layer_cmds.push((layer, cmd.into()));
+        self
+    }
+    /// Sets a custom command to kill
+    pub fn kill_cmd<C: Into<Command>>(mut self, kill_cmd: Option<C>) -> Self {
+        self.kill_cmd = kill_cmd.map(Into::into);
+        self
+    }
+    /// Keeps command alive
+    pub fn keep_alive(mut self, keep_alive: bool) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+    /// Runs command only while network is reachable, stops it while unreachable
+    pub fn gate(mut self, gate: bool) -> Self {
+        self.gate = gate;
+        self
+    }
+    /// Runs command once when network goes down, instead of on every failed check
+    pub fn edge(mut self, edge: bool) -> Self {
+        self.edge = edge;
+        self
+    }
+    /// Sets a command to run when network is back, edge mode
+    ///
+    /// It gets `__PK_OUTAGE_SECONDS`, like command.
+    pub fn recovery_cmd<C: Into<Command>>(mut self, cmd: Option<C>) -> Self {
+        self.recovery_cmd = cmd.map(Into::into);
+        self
+    }
+    /// Runs command again after this time while network is still down, edge mode
+    pub fn repeat_every(mut self, every: Option<Duration>) -> Self {
+        self.repeat_every = every;
+        self
+    }
+    /// Checks network as soon as an interface goes down, default route changes or an address is removed
+    ///
+    /// Changes are debounced, command and hooks get `__PK_NETWORK_CHANGE`. Linux only.
+    pub fn netlink(mut self, debounce: Option<Duration>) -> Self {
+        self.netlink = debounce;
+        self
+    }
+    /// Sets delay to check network for the first time after executing command
+    pub fn wait_after_exec(mut self, wait: Duration) -> Self {
+        self.wait_after_exec = wait;
+        self
+    }
+    /// Sets delay between network checks
+    pub fn network_every(mut self, every: Duration) -> Self {
+        self.network_every = every;
+        self
+    }
+    /// Sets signal to kill command, `None` only checks that the process exists
+    pub fn signal(mut self, signal: Option<Signal>) -> Self {
+        self.signal = signal;
+        self
+    }
+    /// Sets working directory of command
+    pub fn current_dir<P: Into<PathBuf>>(mut self, dir: Option<P>) -> Self {
+        self.current_dir = dir.map(Into::into);
+        self
+    }
+    /// Starts command with an empty environment, besides variables set
+    pub fn env_clear(mut self, clear: bool) -> Self {
+        self.env_clear = clear;
+        self
+    }
+    /// Removes an inherited environment variable from command
+    pub fn env_remove<S: Into<String>>(mut self, key: S) -> Self {
+        self.env_remove.push(key.into());
+        self
+    }
+    /// Sets environment variables for command from a file with `KEY=VALUE` lines
+    pub fn env_file<P: Into<PathBuf>>(mut self, path: Option<P>) -> Self {
+        self.env_file = path.map(Into::into);
+        self
+    }
+    /// Sets an environment variable for command, overrides env file
+    pub fn env<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+    /// Runs command as another user, by name or uid
+    pub fn user<S: Into<String>>(mut self, user: Option<S>) -> Self {
+        self.user = user.map(Into::into);
+        self
+    }
+    /// Runs command with another group, by name or gid, defaults to the user's group
+    pub fn group<S: Into<String>>(mut self, group: Option<S>) -> Self {
+        self.group = group.map(Into::into);
+        self
+    }
+    /// Runs command with supplementary groups, defaults to the user's groups
+    pub fn groups(mut self, groups: Option<Vec<String>>) -> Self {
+        self.groups = groups;
+        self
+    }
+    /// Runs all hooks as another user, by name or uid: check, kill, liveness and every other hook command
+    pub fn hook_user<S: Into<String>>(mut self, user: Option<S>) -> Self {
+        self.hook_user = user.map(Into::into);
+        self
+    }
+    /// Drops privileges of Pingkeeper itself to another user after setup
+    pub fn drop_privileges<S: Into<String>>(mut self, user: Option<S>) -> Self {
+        self.drop_privileges = user.map(Into::into);
+        self
+    }
+    /// Keeps capabilities after dropping privileges, ie: `CAP_NET_RAW`
+    pub fn keep_caps(mut self, caps: Vec<String>) -> Self {
+        self.keep_caps = caps;
+        self
+    }
+    /// Sets resource limits of command
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+    /// Runs command in its own cgroup v2, created at this path
+    pub fn cgroup<P: Into<PathBuf>>(mut self, path: Option<P>) -> Self {
+        self.cgroup = path.map(Into::into);
+        self
+    }
+    /// Limits memory of command's cgroup, in bytes
+    pub fn memory_max(mut self, bytes: Option<u64>) -> Self {
+        self.memory_max = bytes;
+        self
+    }
+    /// Limits CPU of command's cgroup, in percent of one CPU
+    pub fn cpu_max(mut self, percent: Option<u32>) -> Self {
+        self.cpu_max = percent;
+        self
+    }
+    /// Restarts command when its resource usage exceeds these thresholds
+    pub fn thresholds(mut self, thresholds: Thresholds) -> Self {
+        self.thresholds = thresholds;
+        self
+    }
+    /// Sets time between resource usage samples of command
+    pub fn usage_every(mut self, every: Duration) -> Self {
+        self.usage_every = every;
+        self
+    }
+    /// Sets time a threshold must be exceeded before restarting command
+    pub fn exceeded_for(mut self, time: Duration) -> Self {
+        self.exceeded_for = time;
+        self
+    }
+    /// Restarts command when its liveness probe fails, independent from the network check
+    pub fn liveness(mut self, liveness: Option<Liveness>) -> Self {
+        self.liveness = liveness;
+        self
+    }
+    /// Waits for command to be ready instead of `wait_after_exec`, timeout is a failed start
+    pub fn readiness(mut self, readiness: Option<Readiness>) -> Self {
+        self.readiness = readiness;
+        self
+    }
+    /// Adds a rule for output of command
+    pub fn output_rule(mut self, rule: OutputRule) -> Self {
+        self.output_rules.push(rule);
+        self
+    }
+    /// Sets hook command for output rules, it gets `__PK_PID`, `__PK_RULE` and `__PK_MATCH`
+    pub fn output_hook<C: Into<Command>>(mut self, cmd: Option<C>) -> Self {
+        self.output_hook = cmd.map(Into::into);
+        self
+    }
+    /// Sets minimum time between actions of the same output rule
+    pub fn output_cooldown(mut self, cooldown: Duration) -> Self {
+        self.output_cooldown = cooldown;
+        self
+    }
+    /// Sets when to restart command after it exits on its own, and which exit status codes are fatal
+    pub fn exit_policy(mut self, policy: ExitPolicy) -> Self {
+        self.exit_policy = policy;
+        self
+    }
+    /// Sets how to find an already running command to adopt at start, instead of spawning it
+    pub fn adopt(mut self, adoption: Adoption) -> Self {
+        self.adoption = adoption;
+        self
+    }
+    /// Adds a step to the escalation ladder of failed checks, the last one repeats
+    ///
+    /// Only without `edge` and `gate`, and not when a layer command runs instead.
+    pub fn escalation_step(mut self, step: EscalationStep) -> Self {
+        self.escalation_steps.push(step);
+        self
+    }
+    /// Sets time of reachable network to go back to the first escalation step
+    pub fn stable_for(mut self, stable_for: Duration) -> Self {
+        self.stable_for = stable_for;
+        self
+    }
+    /// Sets maximum number of command errors in a row, 0 for infinite
+    pub fn max_errors(mut self, max_errors: usize) -> Self {
+        self.max_errors = max_errors;
+        self
+    }
+    /// Sets log level
+    pub fn log_level(mut self, log_level: LogLevel) -> Self {
+        self.log_level = log_level;
+        self
+    }
+    /// Hides command output
+    pub fn quiet(mut self, quiet: bool) -> Self {
+        self.quiet = quiet;
+        self
+    }
+    /// Adds a callback for every event of the main loop
+    pub fn on_event<F: Fn(&Event) + Send + 'static>(mut self, callback: F) -> Self {
+        self.subscribers.push(Box::new(callback));
+        self
+    }
+    /// Gets a channel receiving every event of the main loop
+    pub fn subscribe(&mut self) -> Receiver<Event> {
+        let (sender, receiver) = channel();
+        self.subscribers.push(Box::new(move |event: &Event| {
+            sender.send(event.clone()).ok();
+        }));
+        receiver
+    }
+    /// Gets a handle to stop this Pingkeeper while running
+    pub fn stop_handle(&self) -> StopHandle {
+        self.stop.clone()
+    }
+}
+
+impl From<Opt> for Pingkeeper {
+    fn from(opt: Opt) -> Self {
         // show errors by default
-        logger(LogLevel::from(opt.verbose + 1))
-    } else {
-        logger(LogLevel::from(opt.verbose))
-    };
-    // hosts to ping
-    let hosts: Vec<String> = opt
-        .hosts
-        .trim()
-        .split(' ')
-        .filter(|h| !h.is_empty())
-        .map(str::to_string)
-        .collect();
-    if hosts.is_empty() {
-        return Err(PingkeeperError::NoHostsToPing);
-    }
-    // network monitor
-    let mut network = NetworkMonitor::new(hosts, Some(opt.port));
-    network.set_ping_opt(opt.ping_opt);
-    if network.set_timeout(opt.timeout as u64).is_err() {
-        return Err(PingkeeperError::InvalidTimeout);
-    }
-    // executor
-    let mut executor = Executor::new(opt.command);
-    // signal
-    executor.set_signal(&opt.signal);
-    // wait options to millis
-    let wait_boot_ms = opt.wait_after_exec * 1000;
-    let wait_check_ms = opt.network_every * 1000;
-    // flags and counters
-    let mut is_executing = false;
-    let mut time_since_last_check: usize = 0;
-    let mut errors_in_a_row: usize = 0;
-    loop {
-        let should_spawn;
-        match executor.is_alive() {
-            Ok(is_alive) => {
-                // Clean exit?
-                if !is_alive && opt.max_errors > 0 {
-                    // Allowed?
-                    if !opt.keep_alive {
-                        errors_in_a_row = 0;
+        let log_level = if !opt.quiet {
+            LogLevel::from(opt.verbose + 1)
+        } else {
+            LogLevel::from(opt.verbose)
+        };
+        // hosts to ping
+        let hosts: Vec<String> = opt
+            .hosts
+            .trim()
+            .split(' ')
+            .filter(|h| !h.is_empty())
+            .map(str::to_string)
+            .collect();
+        // argv or shell command line
+        let command = if !opt.argv.is_empty() {
+            Command::Argv(opt.argv)
+        } else {
+            Command::Shell(opt.command.unwrap_or_default())
+        };
+        // liveness probe of command
+        let liveness_every = Duration::from_secs(opt.liveness_every as u64);
+        let liveness_threshold = opt.liveness_threshold;
+        let liveness_grace = Duration::from_secs(opt.liveness_grace as u64);
+        let liveness = match (opt.liveness_tcp, opt.liveness_http, opt.liveness_cmd) {
+            (Some(address), _, _) => Some(LivenessCheck::Tcp(address)),
+            (None, Some(url), _) => Some(LivenessCheck::Http(url)),
+            (None, None, Some(cmd)) => Some(LivenessCheck::Command(Command::from(cmd))),
+            (None, None, None) => None,
+        }
+        .map(|check| {
+            Liveness::new(check)
+                .every(liveness_every)
+                .threshold(liveness_threshold)
+                .grace(liveness_grace)
+        });
+        // readiness of command
+        let ready_timeout = Duration::from_secs(opt.ready_timeout as u64);
+        let readiness = match (
+            opt.ready_tcp,
+            opt.ready_file,
+            opt.ready_interface,
+            opt.ready_log,
+            opt.ready_notify,
+        ) {
+            (Some(address), _, _, _, _) => Some(ReadinessCheck::Tcp(address)),
+            (None, Some(path), _, _, _) => Some(ReadinessCheck::File(path)),
+            (None, None, Some(name), _, _) => Some(ReadinessCheck::Interface(name)),
+            (None, None, None, Some(regex), _) => Some(ReadinessCheck::Log(regex)),
+            (None, None, None, None, true) => Some(ReadinessCheck::Notify),
+            (None, None, None, None, false) => None,
+        }
+        .map(|check| Readiness::new(check).timeout(ready_timeout));
+        let source = Source::default()
+            .address(opt.source_address)
+            .interface(opt.source_interface);
+        // already running command
+        let mut adoption = Adoption::default();
+        if let Some(path) = opt.adopt_pidfile {
+            adoption = adoption.pidfile(path);
+        }
+        if let Some(pid) = opt.adopt_pid {
+            adoption = adoption.pid(pid);
+        }
+        if let Some(regex) = opt.adopt_cmdline {
+            adoption = adoption.cmdline(regex);
+        }
+        let mut pk = Pingkeeper::new(command)
+            .hosts(hosts)
+            .port(opt.port)
+            .timeout(Duration::from_secs(u64::from(opt.timeout)))
+            .use_ping(opt.use_ping)
+            .ping_opt(opt.ping_opt.clone())
+            .check_cmd(opt.check_cmd)
+            .check_timeout(opt.check_timeout.map(Duration::from_secs))
+            .check_expr(opt.check_expr)
+            .interface_probe(
+                InterfaceProbe::new()
+                    .ipv4(opt.interface_ipv4)
+                    .ipv6(opt.interface_ipv6)
+                    .default_route(opt.interface_default_route),
+            )
+            .source(source.clone())
+            .quality(QualityThresholds {
+                p50: opt.max_p50.map(Duration::from_millis),
+                p95: opt.max_p95.map(Duration::from_millis),
+                loss: opt.max_loss,
+            })
+            .samples(opt.samples)
+            .degraded_cmd(opt.degraded_cmd)
+            .degraded_is_down(opt.degraded_is_down)
+            .diagnose(opt.diagnose)
+            .kill_cmd(opt.kill_cmd)
+            .keep_alive(opt.keep_alive)
+            .gate(opt.gate)
+            .edge(opt.edge)
+            .recovery_cmd(opt.recovery_cmd)
+            .repeat_every(
+                opt.repeat_every
+                    .map(|every| Duration::from_secs(every as u64)),
+            )
+            .netlink(if opt.netlink {
+                Some(Duration::from_millis(opt.netlink_debounce.unwrap_or(1000)))
+            } else {
+                None
+            })
+            .wait_after_exec(Duration::from_secs(opt.wait_after_exec as u64))
+            .network_every(Duration::from_secs(opt.network_every as u64))
+            .signal(Signal::from_str(&opt.signal).ok())
+            .stable_for(Duration::from_secs(opt.stable_for as u64))
+            .adopt(adoption)
+            .max_errors(opt.max_errors)
+            .log_level(log_level)
+            .quiet(opt.quiet)
+            .current_dir(opt.workdir)
+            .env_clear(opt.clear_env)
+            .env_file(opt.env_file)
+            .user(opt.user)
+            .group(opt.group)
+            .groups(opt.groups.map(|groups| split_list(&groups)))
+            .hook_user(opt.hook_user)
+            .drop_privileges(opt.drop_privileges)
+            .keep_caps(
+                opt.keep_caps
+                    .map_or_else(Vec::new, |caps| split_list(&caps)),
+            )
+            .limits(Limits {
+                nofile: opt.limit_nofile,
+                address_space: opt.limit_as,
+                cpu: opt.limit_cpu,
+            })
+            .cgroup(opt.cgroup)
+            .memory_max(opt.memory_max)
+            .cpu_max(opt.cpu_max)
+            .thresholds(Thresholds {
+                rss: opt.max_rss,
+                cpu: opt.max_cpu,
+                fds: opt.max_fds,
+                threads: opt.max_threads,
+            })
+            .usage_every(Duration::from_secs(opt.usage_every as u64))
+            .exceeded_for(Duration::from_secs(opt.exceeded_for as u64))
+            .liveness(liveness)
+            .readiness(readiness)
+            .output_hook(opt.output_hook)
+            .output_cooldown(Duration::from_secs(opt.output_cooldown as u64))
+            .exit_policy(ExitPolicy {
+                restart: opt.restart,
+                success: opt.success_exit_status,
+                fatal: opt.fatal_exit_status,
+            });
+        for spec in opt.host_group {
+            let group = HostGroup::new(spec.name, spec.hosts, spec.action).quorum(spec.quorum);
+            pk = pk.host_group(match spec.probe.as_deref() {
+                Some("tcp") => group.probe(TcpProbe::new(Some(opt.port)).source(source.clone())),
+                Some("ping") => {
+                    group.probe(PingProbe::new(opt.ping_opt.as_str()).source(source.clone()))
+                }
+                Some("http") => group.probe(HttpProbe::new().source(source.clone())),
+                _ => group,
+            });
+        }
+        for step in opt.escalate {
+            pk = pk.escalation_step(step);
+        }
+        for (layer, cmd) in opt.on_layer {
+            pk = pk.layer_cmd(layer, cmd);
+        }
+        for name in opt.interface {
+            pk = pk.interface(name);
+        }
+        for rule in opt.on_output {
+            pk = pk.output_rule(rule);
+        }
+        for key in opt.unset_env {
+            pk = pk.env_remove(key);
+        }
+        for (key, value) in opt.env {
+            pk = pk.env(key, value);
+        }
+        pk
+    }
+}
+
+// Run
+impl Pingkeeper {
+    /// Gets probe to check network: custom > check command > ping > tcp
+    fn network_probe(&self, hook_credentials: &Credentials) -> Arc<dyn Probe> {
+        if let Some(probe) = &self.probe {
+            probe.clone()
+        } else if let Some(cmd) = &self.check_cmd {
+            Arc::new(
+                CommandProbe::new(cmd.clone())
+                    .with_credentials(hook_credentials.clone())
+                    .timeout(self.check_timeout),
+            )
+        } else if self.use_ping {
+            Arc::new(PingProbe::new(self.ping_opt.as_str()).source(self.source.clone()))
+        } else {
+            Arc::new(TcpProbe::new(Some(self.port)).source(self.source.clone()))
+        }
+    }
+    /// Gets probe and hosts of a leaf in the check expression
+    ///
+    /// Hosts are network hosts for commands and for leaves without arguments, interfaces for `interface()`.
+    fn leaf_probe(
+        &self,
+        leaf: &CheckLeaf,
+        hook_credentials: &Credentials,
+    ) -> (Arc<dyn Probe>, Vec<String>) {
+        let hosts = match (leaf.args.is_empty(), leaf.kind) {
+            (true, LeafKind::Interface) => self.interfaces.clone(),
+            (true, _) | (_, LeafKind::Cmd) => self.hosts.clone(),
+            _ => leaf.args.split_whitespace().map(String::from).collect(),
+        };
+        match leaf.kind {
+            LeafKind::Tcp => (
+                Arc::new(TcpProbe::new(Some(self.port)).source(self.source.clone())),
+                hosts,
+            ),
+            LeafKind::Ping => (
+                Arc::new(PingProbe::new(self.ping_opt.as_str()).source(self.source.clone())),
+                hosts,
+            ),
+            LeafKind::Http => (
+                Arc::new(HttpProbe::new().source(self.source.clone())),
+                hosts,
+            ),
+            LeafKind::Cmd => (
+                Arc::new(
+                    CommandProbe::new(leaf.args.as_str())
+                        .with_credentials(hook_credentials.clone())
+                        .timeout(self.check_timeout),
+                ),
+                hosts,
+            ),
+            LeafKind::Interface => (Arc::new(self.interface_probe.clone()), hosts),
+        }
+    }
+    /// Runs the main loop until an error or until stopped with a `StopHandle`
+    pub fn run(&self) -> Result<(), PingkeeperError> {
+        // events, logger is the first consumer
+        let log = event_logger(self.log_level);
+        let emit = |event: Event| {
+            log(&event);
+            for subscriber in &self.subscribers {
+                subscriber(&event);
+            }
+        };
+        // command to run
+        if self.command.is_empty() {
+            return Err(PingkeeperError::NoCommand);
+        }
+        // hosts to ping
+        if self.hosts.is_empty() {
+            return Err(PingkeeperError::NoHostsToPing);
+        }
+        // hook for output rules
+        if self.output_hook.is_none()
+            && self
+                .output_rules
+                .iter()
+                .any(|rule| rule.action == RuleAction::Hook)
+        {
+            return Err(PingkeeperError::NoOutputHook);
+        }
+        // credentials
+        let credentials = Credentials::new(
+            self.user.as_deref(),
+            self.group.as_deref(),
+            self.groups.as_deref(),
+        )?;
+        let hook_credentials = Credentials::new(self.hook_user.as_deref(), None, None)?;
+        // network monitor
+        let mut network = NetworkMonitor::new(self.hosts.clone(), Some(self.port));
+        network.set_ping_opt(self.ping_opt.clone());
+        if network.set_timeout(self.timeout).is_err() {
+            return Err(PingkeeperError::InvalidTimeout);
+        }
+        network.set_probe(self.network_probe(&hook_credentials));
+        network.set_interfaces(self.interfaces.clone(), self.interface_probe.clone());
+        // executor
+        let mut executor = Executor::new(self.command.clone());
+        // signal
+        executor.set_kill_signal(self.signal);
+        executor.set_kill_timeout(self.timeout);
+        // environment
+        if let Some(dir) = &self.current_dir {
+            executor.set_current_dir(dir);
+        }
+        executor.set_env_clear(self.env_clear);
+        for key in &self.env_remove {
+            executor.remove_env(key);
+        }
+        if let Some(path) = &self.env_file {
+            if executor.load_env_file(path).is_err() {
+                return Err(PingkeeperError::InvalidEnvFile);
+            }
+        }
+        for (key, value) in &self.env {
+            executor.set_env(key, value);
+        }
+        // limits and cgroup
+        executor.set_limits(self.limits);
+        let cgroup = match (&self.cgroup, self.memory_max, self.cpu_max) {
+            (Some(path), _, _) => Some(path.clone()),
+            (None, None, None) => None,
+            _ => Some(PathBuf::from(format!(
+                "/sys/fs/cgroup/pingkeeper-{}",
+                process::id()
+            ))),
+        };
+        if let Some(path) = cgroup {
+            match Cgroup::create(&path, self.memory_max, self.cpu_max) {
+                Ok(cgroup) => executor.set_cgroup(cgroup),
+                Err(_) => return Err(PingkeeperError::CannotCreateCgroup),
+            }
+        }
+        // output
+        executor.set_capture(
+            !self.output_rules.is_empty()
+                || self.readiness.as_ref().is_some_and(Readiness::needs_output),
+        );
+        // readiness instead of waiting after exec, socket owned by the child process user
+        let mut readiness = match self
+            .readiness
+            .clone()
+            .map(|readiness| ReadinessMonitor::new(readiness, &credentials))
+        {
+            Some(Ok(readiness)) => Some(readiness),
+            Some(Err(_)) => return Err(PingkeeperError::CannotCreateNotifySocket),
+            None => None,
+        };
+        if let Some(path) = readiness.as_ref().and_then(ReadinessMonitor::notify_socket) {
+            executor.set_env("NOTIFY_SOCKET", &path.to_string_lossy());
+        }
+        // user and groups
+        executor.set_credentials(credentials);
+        executor.set_hook_credentials(hook_credentials.clone());
+        // drop privileges after setup
+        if let Some(user) = &self.drop_privileges {
+            drop_privileges(&Credentials::new(Some(user), None, None)?, &self.keep_caps)?;
+        }
+        // resource usage and liveness
+        let mut usage = UsageMonitor::new(self.thresholds, self.exceeded_for);
+        let mut liveness = self
+            .liveness
+            .clone()
+            .map(|liveness| LivenessMonitor::new(liveness, self.timeout, &hook_credentials));
+        // output rules
+        let mut rules = if self.output_rules.is_empty() {
+            None
+        } else {
+            Some(RuleMonitor::new(
+                self.output_rules.clone(),
+                self.output_cooldown,
+            ))
+        };
+        // network changes
+        let mut netlink = match self.netlink.map(NetlinkListener::start) {
+            Some(Ok(netlink)) => Some(netlink),
+            Some(Err(_)) => return Err(PingkeeperError::CannotListenNetlink),
+            None => None,
+        };
+        // wait options to millis
+        let wait_boot_ms = self.wait_after_exec.as_millis() as usize;
+        let wait_check_ms = self.network_every.as_millis() as usize;
+        let wait_usage_ms = self.usage_every.as_millis() as usize;
+        // flags and counters
+        let mut is_executing = false;
+        let mut child_pid: Option<u32> = None;
+        // spawned or adopted last, for the pidfile
+        let mut last_pid: Option<u32> = None;
+        let mut spawn_reason = SpawnReason::Start;
+        let mut is_killed = false;
+        let mut network_state = NetworkState::Unknown;
+        // degraded on the last check, also when counted as down
+        let mut was_degraded = false;
+        let mut escalation = Escalation::new(self.escalation_steps.clone(), self.stable_for);
+        let mut group_states = vec![NetworkState::Unknown; self.host_groups.len()];
+        let mut is_ready = false;
+        let mut check_now = false;
+        let mut outage_since: Option<Instant> = None;
+        let mut last_edge_run: Option<Instant> = None;
+        let mut restart_child = false;
+        let mut time_since_last_check: usize = 0;
+        let mut time_since_last_usage: usize = 0;
+        let mut errors_in_a_row: usize = 0;
+        // Already running, adopt it instead of spawning another one
+        if let Some(pid) = self.adoption.find(&self.command) {
+            if executor.adopt(pid) {
+                is_executing = true;
+                child_pid = Some(pid);
+                last_pid = Some(pid);
+                is_ready = true;
+                time_since_last_check = wait_boot_ms;
+                emit(Event::ChildAdopted { pid });
+            }
+        }
+        while !self.stop.is_stopped() {
+            let should_spawn;
+            // Ready or waited long enough after spawn?
+            let is_booting = match readiness {
+                Some(_) => !is_ready,
+                None => time_since_last_check < wait_boot_ms,
+            };
+            match executor.is_alive() {
+                Ok(is_alive) => {
+                    // Output since previous tick, all of it if the child process just exited
+                    let lines = if !is_alive && child_pid.is_some() {
+                        executor.read_final_output()
                     } else {
-                        errors_in_a_row += 1;
+                        executor.read_output()
+                    };
+                    if !is_alive {
+                        if let Some(pid) = child_pid.take() {
+                            // Dead, not booting anymore
+                            is_ready = true;
+                            let oom = executor.is_oom_killed();
+                            emit(Event::ChildExited {
+                                pid,
+                                status: executor.get_exit_status(),
+                                oom,
+                            });
+                            if oom {
+                                spawn_reason = SpawnReason::OutOfMemory;
+                            } else if !is_killed {
+                                spawn_reason = SpawnReason::ChildExited;
+                            }
+                            // Last output matches a rule? Restarting overrides the exit policy
+                            if let Some(rules) = &mut rules {
+                                let hook = &self.output_hook;
+                                let (restart, _) =
+                                    check_rules(rules, &lines, pid, hook, &mut executor, &emit);
+                                if let (Some(reason), false) = (restart, is_killed) {
+                                    spawn_reason = reason;
+                                    restart_child = true;
+                                }
+                            }
+                            // Exited on its own, restart?
+                            if !is_killed && !restart_child {
+                                let status = executor.get_exit_status();
+                                match self.exit_policy.decide(status) {
+                                    ExitDecision::Fatal => {
+                                        emit(Event::FatalExit { pid, status });
+                                        return Err(PingkeeperError::FatalExitStatus);
+                                    }
+                                    ExitDecision::Stop if self.keep_alive || self.gate => {
+                                        emit(Event::NotRestarted {
+                                            pid,
+                                            policy: self.exit_policy.restart,
+                                        });
+                                        return Ok(());
+                                    }
+                                    _ => (),
+                                }
+                                if self.gate && self.max_errors > 0 {
+                                    errors_in_a_row += 1;
+                                }
+                            }
+                        }
                     }
-                }
-                if opt.keep_alive && !is_alive {
-                    logger(LogLevel::WARN, String::from("Child process is dead"));
-                    should_spawn = true;
-                } else if (is_executing && time_since_last_check < wait_boot_ms)
-                    || (!is_executing && time_since_last_check < wait_check_ms)
-                {
-                    should_spawn = false;
-                } else {
-                    is_executing = false;
-                    if network.check(&opt.check_cmd, opt.use_ping).is_ok() {
-                        logger(LogLevel::DEBUG, String::from("Network reachable"));
+                    // Clean exit?
+                    if !is_alive && self.max_errors > 0 {
+                        // Allowed?
+                        if restart_child || self.gate {
+                            // Restarted by pingkeeper, failed starts already counted
+                            // Gate, exits on its own counted once
+                        } else if !self.keep_alive {
+                            errors_in_a_row = 0;
+                        } else {
+                            errors_in_a_row += 1;
+                        }
+                    }
+                    // Network changed, check now
+                    if let Some(netlink) = &mut netlink {
+                        let changes = netlink.changes();
+                        if !changes.is_empty() {
+                            let change = changes
+                                .iter()
+                                .map(ToString::to_string)
+                                .collect::<Vec<String>>()
+                                .join(",");
+                            executor.set_context("__PK_NETWORK_CHANGE", &change);
+                            emit(Event::NetworkChanged { changes });
+                            check_now = true;
+                        }
+                    }
+                    // Child process unhealthy?
+                    let mut restart = None;
+                    let mut kill_child = false;
+                    if let (true, Some(pid)) = (is_alive, child_pid) {
+                        // Ready yet?
+                        if let (false, Some(readiness)) = (is_ready, &mut readiness) {
+                            match readiness.poll(&lines) {
+                                ReadyState::Ready => {
+                                    is_ready = true;
+                                    emit(Event::ChildReady { pid });
+                                }
+                                ReadyState::TimedOut => {
+                                    // Failed start
+                                    if self.max_errors > 0 {
+                                        errors_in_a_row += 1;
+                                    }
+                                    emit(Event::ReadyTimeout { pid });
+                                    restart = Some(SpawnReason::NotReady);
+                                }
+                                ReadyState::Waiting => (),
+                            }
+                        }
+                        // Output matches a rule?
+                        if let Some(rules) = &mut rules {
+                            let hook = &self.output_hook;
+                            let (rule_restart, rule_kill) =
+                                check_rules(rules, &lines, pid, hook, &mut executor, &emit);
+                            restart = restart.or(rule_restart);
+                            kill_child |= rule_kill;
+                        }
+                        // Resource usage over thresholds?
+                        if restart.is_none()
+                            && !self.thresholds.is_empty()
+                            && time_since_last_usage >= wait_usage_ms
+                        {
+                            time_since_last_usage = 0;
+                            if let Some((metric, usage)) = usage.check(pid) {
+                                emit(Event::ThresholdExceeded { pid, metric, usage });
+                                restart = Some(SpawnReason::ThresholdExceeded);
+                            }
+                        }
+                        // Liveness probe failed?
+                        if let (None, Some(liveness)) = (restart, &mut liveness) {
+                            if let Some(outcome) = liveness.check() {
+                                let failures = liveness.failures();
+                                emit(Event::LivenessChecked {
+                                    pid,
+                                    outcome,
+                                    failures,
+                                });
+                                if liveness.is_failed() {
+                                    emit(Event::LivenessFailed { pid, failures });
+                                    restart = Some(SpawnReason::LivenessFailed);
+                                }
+                            }
+                        }
+                    }
+                    let is_gate_open = self.gate
+                        && (network_state == NetworkState::Reachable
+                            || network_state == NetworkState::Degraded);
+                    if !is_alive && (self.keep_alive || is_gate_open || restart_child) {
+                        restart_child = false;
+                        should_spawn = true;
+                    } else if let Some(reason) = restart {
+                        spawn_reason = reason;
+                        // kill now, spawn again once dead
+                        restart_child = true;
+                        should_spawn = true;
+                    } else if kill_child {
+                        // kill now, spawn again as usual
+                        spawn_reason = SpawnReason::OutputMatched;
+                        should_spawn = true;
+                    } else if (is_executing && is_booting)
+                        || (!is_executing && time_since_last_check < wait_check_ms && !check_now)
+                    {
                         should_spawn = false;
                     } else {
-                        logger(LogLevel::WARN, String::from("Network unreachable"));
-                        should_spawn = true;
+                        is_executing = false;
+                        check_now = false;
+                        emit(Event::CheckStarted);
+                        let (results, expr_reachable) = match &self.check_expr {
+                            Some(expr) => {
+                                // Required interfaces first, as without an expression,
+                                // then leaves from left to right until the result is known
+                                let mut results = network.probe_interfaces();
+                                let reachable = results.is_empty()
+                                    && expr.evaluate(&mut |leaf: &CheckLeaf| {
+                                        let (probe, hosts) =
+                                            self.leaf_probe(leaf, &hook_credentials);
+                                        let outcomes = network
+                                            .probe_group(&hosts, Some(&probe), 1)
+                                            .unwrap_or_default();
+                                        let passed = outcomes.iter().any(|outcome| outcome.success);
+                                        emit(Event::LeafChecked {
+                                            leaf: leaf.to_string(),
+                                            passed,
+                                        });
+                                        results.extend(outcomes);
+                                        passed
+                                    });
+                                (results, Some(reachable))
+                            }
+                            None => (network.probe_hosts().unwrap_or_default(), None),
+                        };
+                        // Host groups, all of them on every check
+                        let mut groups_up = true;
+                        for (group, group_state) in
+                            self.host_groups.iter().zip(group_states.iter_mut())
+                        {
+                            let results = network
+                                .probe_group(&group.hosts, group.probe.as_ref(), group.quorum)
+                                .unwrap_or_default();
+                            let is_up = group.is_up(&results);
+                            emit(Event::GroupChecked {
+                                group: group.name.clone(),
+                                reachable: is_up,
+                                results,
+                            });
+                            let state = if is_up {
+                                NetworkState::Reachable
+                            } else {
+                                NetworkState::Unreachable
+                            };
+                            if state != *group_state {
+                                emit(Event::GroupStateChanged {
+                                    group: group.name.clone(),
+                                    from: *group_state,
+                                    to: state,
+                                });
+                                *group_state = state;
+                                if let (false, GroupAction::Hook(cmd)) = (is_up, &group.action) {
+                                    executor.run_hook(cmd.clone(), &[("__PK_GROUP", &group.name)]);
+                                }
+                            }
+                            groups_up &= is_up || group.action != GroupAction::Command;
+                        }
+                        let reachable = expr_reachable
+                            .unwrap_or_else(|| results.iter().any(|result| result.success))
+                            && groups_up;
+                        // Quality of the first host that answered
+                        let mut degraded = false;
+                        let answered = results.iter().find(|result| result.success);
+                        if let (true, true, Some(answered)) = (
+                            reachable && !self.quality.is_empty(),
+                            self.check_expr.is_none(),
+                            answered,
+                        ) {
+                            let target = answered.target.clone();
+                            let quality = network.sample(&target, self.samples);
+                            degraded = self.quality.is_degraded(&quality);
+                            if degraded && !was_degraded {
+                                if let Some(cmd) = &self.degraded_cmd {
+                                    let millis = |latency: Option<Duration>| {
+                                        latency
+                                            .map(|latency| latency.as_millis().to_string())
+                                            .unwrap_or_default()
+                                    };
+                                    executor.run_hook(
+                                        cmd.clone(),
+                                        &[
+                                            ("__PK_P50_MS", &millis(quality.p50)),
+                                            ("__PK_P95_MS", &millis(quality.p95)),
+                                            ("__PK_LOSS_PERCENT", &quality.loss().to_string()),
+                                        ],
+                                    );
+                                }
+                            }
+                            emit(Event::QualityMeasured {
+                                target,
+                                quality,
+                                degraded,
+                            });
+                        }
+                        was_degraded = degraded;
+                        let reachable = reachable && !(degraded && self.degraded_is_down);
+                        emit(Event::CheckFinished { reachable, results });
+                        // Layer of failure, and its command if any
+                        let mut layer_cmd = None;
+                        if !reachable && self.diagnose {
+                            let layer = network.diagnose();
+                            emit(Event::Diagnosed { layer });
+                            executor.set_context("__PK_FAILURE_LAYER", &layer.to_string());
+                            layer_cmd = self
+                                .layer_cmds
+                                .iter()
+                                .find(|(cmd_layer, _)| *cmd_layer == layer)
+                                .map(|(_, cmd)| cmd.clone());
+                        }
+                        // degraded and counted as down is unreachable
+                        let state = if !reachable {
+                            NetworkState::Unreachable
+                        } else if degraded {
+                            NetworkState::Degraded
+                        } else {
+                            NetworkState::Reachable
+                        };
+                        let previous_state = network_state;
+                        if state != network_state {
+                            emit(Event::StateChanged {
+                                from: network_state,
+                                to: state,
+                            });
+                            network_state = state;
+                        }
+                        if self.edge {
+                            // Run once per outage, or again after a while
+                            let mut should_run = false;
+                            if !reachable {
+                                let outage_start = *outage_since.get_or_insert_with(Instant::now);
+                                let is_repeat = match (self.repeat_every, last_edge_run) {
+                                    (Some(every), Some(last)) => last.elapsed() >= every,
+                                    _ => false,
+                                };
+                                if (previous_state != state || is_repeat) && !is_alive {
+                                    should_run = true;
+                                    spawn_reason = SpawnReason::NetworkUnreachable;
+                                    last_edge_run = Some(Instant::now());
+                                    executor.set_spawn_env(
+                                        "__PK_OUTAGE_SECONDS",
+                                        &outage_start.elapsed().as_secs().to_string(),
+                                    );
+                                }
+                            } else if let Some(outage_start) = outage_since.take() {
+                                let outage = outage_start.elapsed();
+                                last_edge_run = None;
+                                emit(Event::Recovered { outage });
+                                if let Some(cmd) = &self.recovery_cmd {
+                                    executor.run_hook(
+                                        cmd.clone(),
+                                        &[("__PK_OUTAGE_SECONDS", &outage.as_secs().to_string())],
+                                    );
+                                }
+                            }
+                            match (should_run, layer_cmd) {
+                                (true, Some(cmd)) => {
+                                    executor.run_hook(cmd, &[]);
+                                    should_spawn = false;
+                                }
+                                _ => should_spawn = should_run,
+                            }
+                        } else if self.gate {
+                            // Run only while reachable
+                            should_spawn = reachable != is_alive;
+                            if reachable {
+                                spawn_reason = SpawnReason::NetworkReachable;
+                            }
+                        } else if let Some(cmd) = layer_cmd {
+                            executor.run_hook(cmd, &[]);
+                            should_spawn = false;
+                        } else {
+                            let mut should_run = !reachable;
+                            if !reachable {
+                                spawn_reason = SpawnReason::NetworkUnreachable;
+                                // Escalation ladder, if any; a restart is a kill and then a spawn
+                                // on the next check, only the spawn counts
+                                let is_killing = is_alive
+                                    && !self.keep_alive
+                                    && escalation.next_action() == Some(&EscalationAction::Restart);
+                                let escalated = if is_killing {
+                                    None
+                                } else {
+                                    escalation.escalate()
+                                };
+                                if let Some((step, attempt, action)) = escalated {
+                                    emit(Event::Escalated {
+                                        step,
+                                        attempt,
+                                        action: action.clone(),
+                                    });
+                                    match action {
+                                        EscalationAction::Restart => {}
+                                        EscalationAction::Hook(cmd) => {
+                                            executor.run_hook(
+                                                cmd,
+                                                &[("__PK_ESCALATION_STEP", &step.to_string())],
+                                            );
+                                            should_run = false;
+                                        }
+                                        EscalationAction::Exit => {
+                                            emit(Event::GaveUp {
+                                                errors: escalation.failures(),
+                                            });
+                                            return Err(PingkeeperError::TooManyErrors);
+                                        }
+                                    }
+                                }
+                            } else if escalation.stabilize() {
+                                emit(Event::EscalationReset);
+                            }
+                            should_spawn = should_run;
+                        }
+                        time_since_last_check = 0;
                     }
-                    time_since_last_check = 0;
                 }
-            }
-            Err(err) => {
-                if opt.max_errors > 0 {
-                    errors_in_a_row += 1;
+                Err(err) => {
+                    if self.max_errors > 0 {
+                        errors_in_a_row += 1;
+                    }
+                    emit(Event::ChildError {
+                        error: err.to_string(),
+                    });
+                    should_spawn = true;
                 }
-                logger(LogLevel::ERROR, format!("Command error -> {}", err));
-                should_spawn = true;
             }
-        }
 
-        if opt.max_errors > 0 && errors_in_a_row > opt.max_errors {
-            return Err(PingkeeperError::TooManyErrors);
-        }
+            if self.max_errors > 0 && errors_in_a_row > self.max_errors {
+                emit(Event::GaveUp {
+                    errors: errors_in_a_row,
+                });
+                return Err(PingkeeperError::TooManyErrors);
+            }
 
-        // Check process launch
-        if should_spawn {
-            // Reset time to check
-            time_since_last_check = 0;
-            logger(
-                LogLevel::DEBUG,
-                String::from("Should spawn a child process"),
-            );
-            match spawn_controller(&mut executor, &opt.kill_cmd, opt.quiet) {
-                // Kill
-                Ok(SpawnOk::KillOk(pid)) => {
-                    logger(LogLevel::INFO, format!("Kill done for pid {}", pid))
-                }
-                Err(SpawnErr::KillErr(pid)) => {
-                    logger(LogLevel::ERROR, format!("Cannot kill pid {}", pid))
-                }
-                // Spawn
-                Ok(SpawnOk::SpawnOk(pid)) => {
-                    is_executing = true;
-                    logger(
-                        LogLevel::INFO,
-                        format!("Child process starting with pid {}", pid),
-                    )
-                }
-                Err(SpawnErr::SpawnErr) => logger(
-                    LogLevel::ERROR,
-                    String::from("Child process is dead on boot"),
-                ),
-            };
-        } else if !is_executing && time_since_last_check >= wait_check_ms {
-            // Time to check network again
-            time_since_last_check = 0;
+            // Check process launch
+            if should_spawn {
+                // Reset time to check
+                time_since_last_check = 0;
+                match spawn_controller(&mut executor, &self.kill_cmd, self.quiet, spawn_reason) {
+                    // Kill
+                    Ok(SpawnOk::KillOk(pid)) => {
+                        is_killed = true;
+                        emit(Event::KillSent { pid })
+                    }
+                    Err(SpawnErr::KillErr(pid)) => emit(Event::KillFailed { pid }),
+                    // Spawn
+                    Ok(SpawnOk::SpawnOk(pid)) => {
+                        is_executing = true;
+                        is_killed = false;
+                        child_pid = Some(pid);
+                        last_pid = Some(pid);
+                        if let Some(liveness) = &mut liveness {
+                            liveness.reset();
+                        }
+                        if let Some(readiness) = &mut readiness {
+                            readiness.reset();
+                        }
+                        is_ready = false;
+                        if let Some(path) = self.adoption.get_pidfile() {
+                            // Best effort, adopting is skipped without it
+                            write_pidfile(path, pid).ok();
+                        }
+                        emit(Event::ChildSpawned { pid })
+                    }
+                    Err(SpawnErr::SpawnErr) => emit(Event::SpawnFailed),
+                };
+            } else if !is_executing && time_since_last_check >= wait_check_ms {
+                // Time to check network again
+                time_since_last_check = 0;
+            }
+            sleep(Duration::from_millis(CHECK_MS as u64));
+            // Add time to timers
+            time_since_last_check += CHECK_MS;
+            time_since_last_usage += CHECK_MS;
+        }
+        // Stopped, kill child process if alive
+        if executor.get_pid().is_some() {
+            match spawn_controller(&mut executor, &self.kill_cmd, self.quiet, spawn_reason) {
+                Ok(SpawnOk::KillOk(pid)) => emit(Event::KillSent { pid }),
+                Err(SpawnErr::KillErr(pid)) => emit(Event::KillFailed { pid }),
+                _ => (),
+            }
+        }
+        // Nothing to adopt once dead, but a stale pid
+        if let (Some(path), Some(pid)) = (self.adoption.get_pidfile(), last_pid) {
+            if executor.get_pid().is_none() {
+                remove_pidfile(path, pid).ok();
+            }
         }
-        sleep(Duration::from_millis(CHECK_MS as u64));
-        // Add time to timer
-        time_since_last_check += CHECK_MS;
+        Ok(())
     }
 }
 
+/// Splits a comma or space separated list
+fn split_list(list: &str) -> Vec<String> {
+    list.split([',', ' '])
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Monitorizes that network is reachable and, in case of failure, runs a command
+pub fn pingkeeper(opt: Opt) -> Result<(), PingkeeperError> {
+    Pingkeeper::from(opt).run()
+}
 #[cfg(test)]
 mod tests {
     use super::*;
     use executor::Executor;
+    use std::fs;
+    use std::thread;
+
+    /// Network that goes up and down at will
+    #[derive(Clone, Default)]
+    struct Switch(Arc<AtomicBool>);
+
+    impl Switch {
+        fn set(&self, up: bool) {
+            self.0.store(up, Ordering::SeqCst);
+        }
+    }
+
+    impl Probe for Switch {
+        fn name(&self) -> &str {
+            "switch"
+        }
+        fn probe(&self, target: &str, _timeout: Duration) -> ProbeOutcome {
+            if self.0.load(Ordering::SeqCst) {
+                ProbeOutcome::success(target, None)
+            } else {
+                ProbeOutcome::failure(target, "down")
+            }
+        }
+    }
+
+    /// Quiet pingkeeper checking a switch every 100 ms
+    fn switched(command: &str, switch: &Switch) -> Pingkeeper {
+        Pingkeeper::new(command)
+            .hosts(vec![String::from("switch")])
+            .probe(switch.clone())
+            .wait_after_exec(Duration::from_millis(100))
+            .network_every(Duration::from_millis(100))
+            .signal(Some(Signal::SIGTERM))
+            .quiet(true)
+            .log_level(LogLevel::QUIET)
+    }
+
+    /// Waits for an event, returns it with the events before; panics after 5 seconds
+    fn wait_for<F: Fn(&Event) -> bool>(events: &Receiver<Event>, found: F) -> (Event, Vec<Event>) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut before = vec![];
+        loop {
+            match events.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(event) if found(&event) => return (event, before),
+                Ok(event) => before.push(event),
+                Err(_) => panic!("Event not found after {:?}", before),
+            }
+        }
+    }
+
+    /// Events within a while
+    fn events_for(events: &Receiver<Event>, duration: Duration) -> Vec<Event> {
+        let deadline = Instant::now() + duration;
+        let mut found = vec![];
+        while let Ok(event) =
+            events.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            found.push(event);
+        }
+        found
+    }
+
+    fn is_spawned(event: &Event) -> bool {
+        matches!(event, Event::ChildSpawned { .. })
+    }
 
     #[test]
     fn without_hosts() {
         let opt = Opt {
-            command: String::from("echo"),
+            command: Some(String::from("echo")),
+            argv: vec![],
             hosts: String::new(),
             keep_alive: false,
+            gate: false,
+            edge: false,
+            recovery_cmd: None,
+            repeat_every: None,
+            netlink: false,
+            netlink_debounce: None,
             max_errors: 0,
             network_every: 5,
             ping_opt: String::from("-c1"),
@@ -229,7 +1652,70 @@ mod tests {
             wait_after_exec: 5,
             timeout: 2,
             check_cmd: None,
+            check_timeout: None,
+            check_expr: None,
+            interface: vec![],
+            interface_ipv4: false,
+            interface_ipv6: false,
+            interface_default_route: false,
+            source_address: None,
+            source_interface: None,
+            host_group: vec![],
+            max_p50: None,
+            max_p95: None,
+            max_loss: None,
+            samples: 5,
+            degraded_cmd: None,
+            degraded_is_down: false,
+            diagnose: false,
+            on_layer: vec![],
+            escalate: vec![],
+            stable_for: 300,
+            adopt_pidfile: None,
+            adopt_pid: None,
+            adopt_cmdline: None,
             kill_cmd: None,
+            workdir: None,
+            env: vec![],
+            unset_env: vec![],
+            clear_env: false,
+            env_file: None,
+            user: None,
+            group: None,
+            groups: None,
+            hook_user: None,
+            drop_privileges: None,
+            keep_caps: None,
+            limit_nofile: None,
+            limit_as: None,
+            limit_cpu: None,
+            cgroup: None,
+            memory_max: None,
+            cpu_max: None,
+            max_rss: None,
+            max_cpu: None,
+            max_fds: None,
+            max_threads: None,
+            usage_every: 5,
+            exceeded_for: 0,
+            liveness_tcp: None,
+            liveness_http: None,
+            liveness_cmd: None,
+            liveness_every: 10,
+            liveness_threshold: 3,
+            liveness_grace: 30,
+            ready_tcp: None,
+            ready_file: None,
+            ready_interface: None,
+            ready_log: None,
+            ready_notify: false,
+            ready_timeout: 60,
+            on_output: vec![],
+            output_hook: None,
+            output_cooldown: 60,
+            restart: RestartPolicy::Always,
+            success_exit_status: vec![],
+            fatal_exit_status: vec![],
         };
         let error = pingkeeper(opt);
         assert!(error.is_err());
@@ -238,9 +1724,16 @@ mod tests {
     #[test]
     fn max_errors() {
         let opt = Opt {
-            command: String::from("__pingkeeper__test__command__"),
+            command: Some(String::from("__pingkeeper__test__command__")),
+            argv: vec![],
             hosts: String::from("0.0.0.0"),
             keep_alive: true,
+            gate: false,
+            edge: false,
+            recovery_cmd: None,
+            repeat_every: None,
+            netlink: false,
+            netlink_debounce: None,
             max_errors: 2,
             network_every: 5,
             ping_opt: String::from("-c1"),
@@ -252,30 +1745,522 @@ mod tests {
             wait_after_exec: 1,
             timeout: 2,
             check_cmd: None,
+            check_timeout: None,
+            check_expr: None,
+            interface: vec![],
+            interface_ipv4: false,
+            interface_ipv6: false,
+            interface_default_route: false,
+            source_address: None,
+            source_interface: None,
+            host_group: vec![],
+            max_p50: None,
+            max_p95: None,
+            max_loss: None,
+            samples: 5,
+            degraded_cmd: None,
+            degraded_is_down: false,
+            diagnose: false,
+            on_layer: vec![],
+            escalate: vec![],
+            stable_for: 300,
+            adopt_pidfile: None,
+            adopt_pid: None,
+            adopt_cmdline: None,
             kill_cmd: None,
+            workdir: None,
+            env: vec![],
+            unset_env: vec![],
+            clear_env: false,
+            env_file: None,
+            user: None,
+            group: None,
+            groups: None,
+            hook_user: None,
+            drop_privileges: None,
+            keep_caps: None,
+            limit_nofile: None,
+            limit_as: None,
+            limit_cpu: None,
+            cgroup: None,
+            memory_max: None,
+            cpu_max: None,
+            max_rss: None,
+            max_cpu: None,
+            max_fds: None,
+            max_threads: None,
+            usage_every: 5,
+            exceeded_for: 0,
+            liveness_tcp: None,
+            liveness_http: None,
+            liveness_cmd: None,
+            liveness_every: 10,
+            liveness_threshold: 3,
+            liveness_grace: 30,
+            ready_tcp: None,
+            ready_file: None,
+            ready_interface: None,
+            ready_log: None,
+            ready_notify: false,
+            ready_timeout: 60,
+            on_output: vec![],
+            output_hook: None,
+            output_cooldown: 60,
+            restart: RestartPolicy::Always,
+            success_exit_status: vec![],
+            fatal_exit_status: vec![],
         };
         let error = pingkeeper(opt);
         assert!(error.is_err());
         assert_eq!(error.unwrap_err(), PingkeeperError::TooManyErrors);
     }
     #[test]
+    fn without_command() {
+        let error = Pingkeeper::new("  ").run();
+        assert_eq!(error.unwrap_err(), PingkeeperError::NoCommand);
+        let error = Pingkeeper::new(Vec::<String>::new()).run();
+        assert_eq!(error.unwrap_err(), PingkeeperError::NoCommand);
+    }
+    #[test]
+    fn unknown_user() {
+        let error = Pingkeeper::new("echo")
+            .user(Some("__pk__test__user__"))
+            .run();
+        assert_eq!(error.unwrap_err(), PingkeeperError::UnknownUser);
+        let error = Pingkeeper::new("echo")
+            .hook_user(Some("__pk__test__user__"))
+            .run();
+        assert_eq!(error.unwrap_err(), PingkeeperError::UnknownUser);
+    }
+    #[test]
+    fn builder_without_hosts() {
+        let error = Pingkeeper::new("echo").hosts(vec![]).run();
+        assert_eq!(error.unwrap_err(), PingkeeperError::NoHostsToPing);
+    }
+    #[test]
+    fn builder_invalid_timeout() {
+        let error = Pingkeeper::new("echo")
+            .timeout(Duration::from_secs(0))
+            .run();
+        assert_eq!(error.unwrap_err(), PingkeeperError::InvalidTimeout);
+    }
+    #[test]
+    fn stop_handle() {
+        let pk = Pingkeeper::new("cat")
+            .hosts(vec![String::from("127.0.0.1:0")])
+            .keep_alive(true)
+            .signal(Some(Signal::SIGTERM))
+            .quiet(true)
+            .log_level(LogLevel::QUIET);
+        let stop = pk.stop_handle();
+        let supervisor = std::thread::spawn(move || pk.run());
+        sleep(Duration::from_millis(300));
+        assert!(!stop.is_stopped());
+        stop.stop();
+        assert!(supervisor.join().unwrap().is_ok());
+    }
+    #[test]
+    fn events() {
+        let mut pk = Pingkeeper::new("cat")
+            .hosts(vec![String::from("127.0.0.1:0")])
+            .wait_after_exec(Duration::from_millis(100))
+            .network_every(Duration::from_millis(100))
+            .signal(Some(Signal::SIGTERM))
+            .quiet(true)
+            .log_level(LogLevel::QUIET);
+        let events = pk.subscribe();
+        let stop = pk.stop_handle();
+        let supervisor = std::thread::spawn(move || pk.run());
+        assert_eq!(events.recv().unwrap(), Event::CheckStarted);
+        match events.recv().unwrap() {
+            Event::CheckFinished { reachable, results } => {
+                assert!(!reachable);
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].target, "127.0.0.1:0");
+            }
+            event => panic!("Invalid event {:?}", event),
+        }
+        assert_eq!(
+            events.recv().unwrap(),
+            Event::StateChanged {
+                from: NetworkState::Unknown,
+                to: NetworkState::Unreachable
+            }
+        );
+        match events.recv().unwrap() {
+            Event::ChildSpawned { .. } => {}
+            event => panic!("Invalid event {:?}", event),
+        }
+        stop.stop();
+        assert!(supervisor.join().unwrap().is_ok());
+    }
+    #[test]
+    fn output_rule_on_exit() {
+        let switch = Switch::default();
+        let mut pk = switched("echo AUTH_FAILED; exit 1", &switch)
+            .output_rule("warn:AUTH_FAILED".parse().unwrap());
+        let events = pk.subscribe();
+        let stop = pk.stop_handle();
+        let supervisor = thread::spawn(move || pk.run());
+        let (spawned, _) = wait_for(&events, |event| matches!(event, Event::ChildSpawned { .. }));
+        let (exited, _) = wait_for(&events, |event| matches!(event, Event::ChildExited { .. }));
+        // output of the exited child process, after its exit
+        let (matched, _) = wait_for(&events, |event| {
+            matches!(event, Event::OutputMatched { .. })
+        });
+        match (spawned, exited, matched) {
+            (
+                Event::ChildSpawned { pid },
+                Event::ChildExited { pid: exited, .. },
+                Event::OutputMatched {
+                    pid: matched, line, ..
+                },
+            ) => {
+                assert_eq!((exited, matched), (pid, pid));
+                assert_eq!(line, "AUTH_FAILED");
+            }
+            events => panic!("Invalid events {:?}", events),
+        }
+        stop.stop();
+        assert!(supervisor.join().unwrap().is_ok());
+    }
+    #[test]
+    fn gate() {
+        let switch = Switch::default();
+        let mut pk = switched("sleep 30", &switch).gate(true);
+        let events = pk.subscribe();
+        let stop = pk.stop_handle();
+        let supervisor = thread::spawn(move || pk.run());
+        // closed while unreachable
+        let before = events_for(&events, Duration::from_millis(500));
+        assert!(before.contains(&Event::CheckStarted));
+        assert!(!before.iter().any(is_spawned));
+        // open once reachable
+        switch.set(true);
+        wait_for(&events, is_spawned);
+        // closed again, child process stopped and not restarted
+        switch.set(false);
+        wait_for(&events, |event| matches!(event, Event::KillSent { .. }));
+        wait_for(&events, |event| matches!(event, Event::ChildExited { .. }));
+        let after = events_for(&events, Duration::from_millis(700));
+        assert!(after.contains(&Event::CheckStarted));
+        assert!(!after.iter().any(is_spawned));
+        // and open again
+        switch.set(true);
+        wait_for(&events, is_spawned);
+        stop.stop();
+        assert!(supervisor.join().unwrap().is_ok());
+    }
+    #[test]
+    fn on_layer() {
+        let path = std::env::temp_dir().join(format!("pk-test-layer-{}", process::id()));
+        fs::remove_file(&path).ok();
+        // a missing required interface is a link failure
+        let mut pk = switched("sleep 30", &Switch::default())
+            .interface("pk-missing0")
+            .diagnose(true)
+            .layer_cmd(
+                FailureLayer::Link,
+                format!("echo $__PK_FAILURE_LAYER > {}", path.display()),
+            );
+        let events = pk.subscribe();
+        let stop = pk.stop_handle();
+        let supervisor = thread::spawn(move || pk.run());
+        wait_for(&events, |event| {
+            *event
+                == Event::Diagnosed {
+                    layer: FailureLayer::Link,
+                }
+        });
+        // instead of the command
+        let after = events_for(&events, Duration::from_millis(500));
+        assert!(!after.iter().any(is_spawned));
+        stop.stop();
+        assert!(supervisor.join().unwrap().is_ok());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "link\n");
+        fs::remove_file(&path).ok();
+    }
+    #[test]
+    fn exited_before_ready() {
+        let path = std::env::temp_dir().join(format!("pk-test-never-ready-{}", process::id()));
+        fs::remove_file(&path).ok();
+        // one-shot command, never ready
+        let mut pk = switched("true", &Switch::default())
+            .readiness(Some(Readiness::new(ReadinessCheck::File(path))));
+        let events = pk.subscribe();
+        let stop = pk.stop_handle();
+        let supervisor = thread::spawn(move || pk.run());
+        wait_for(&events, |event| matches!(event, Event::ChildExited { .. }));
+        // still checking, and running it again
+        wait_for(&events, |event| *event == Event::CheckStarted);
+        wait_for(&events, is_spawned);
+        stop.stop();
+        assert!(supervisor.join().unwrap().is_ok());
+    }
+    #[test]
+    fn pidfile() {
+        let path = std::env::temp_dir().join(format!("pk-test-pidfile-{}", process::id()));
+        fs::remove_file(&path).ok();
+        let mut pk =
+            switched("sleep 30", &Switch::default()).adopt(Adoption::default().pidfile(&path));
+        let events = pk.subscribe();
+        let stop = pk.stop_handle();
+        let supervisor = thread::spawn(move || pk.run());
+        let (spawned, _) = wait_for(&events, is_spawned);
+        let pid = match spawned {
+            Event::ChildSpawned { pid } => pid,
+            _ => unreachable!(),
+        };
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", pid));
+        // removed once the child process is stopped
+        stop.stop();
+        assert!(supervisor.join().unwrap().is_ok());
+        assert!(!path.exists());
+    }
+    #[test]
+    fn escalation() {
+        let path = std::env::temp_dir().join(format!("pk-test-escalation-{}", process::id()));
+        fs::remove_file(&path).ok();
+        let hook = format!("echo $__PK_ESCALATION_STEP > {}", path.display());
+        let mut pk = switched("sleep 30", &Switch::default())
+            .escalation_step(EscalationStep::new(EscalationAction::Restart, 2))
+            .escalation_step(EscalationStep::new(
+                EscalationAction::Hook(Command::from(hook.as_str())),
+                1,
+            ))
+            .escalation_step(EscalationStep::new(EscalationAction::Exit, 1));
+        let events = pk.subscribe();
+        let supervisor = thread::spawn(move || pk.run());
+        let is_escalated = |event: &Event| matches!(event, Event::Escalated { .. });
+        // run, and restarted once: killed and spawned again
+        for attempt in 1..=2 {
+            let (restart, _) = wait_for(&events, is_escalated);
+            assert_eq!(
+                restart,
+                Event::Escalated {
+                    step: 1,
+                    attempt,
+                    action: EscalationAction::Restart,
+                }
+            );
+            wait_for(&events, is_spawned);
+        }
+        // hook, instead of restarting
+        let (hook, before) = wait_for(&events, is_escalated);
+        assert!(matches!(
+            hook,
+            Event::Escalated {
+                step: 2,
+                action: EscalationAction::Hook(_),
+                ..
+            }
+        ));
+        assert!(!before.iter().any(is_spawned));
+        // and exit
+        let (exit, _) = wait_for(&events, is_escalated);
+        assert!(matches!(
+            exit,
+            Event::Escalated {
+                step: 3,
+                action: EscalationAction::Exit,
+                ..
+            }
+        ));
+        wait_for(&events, |event| matches!(event, Event::GaveUp { .. }));
+        assert_eq!(
+            supervisor.join().unwrap(),
+            Err(PingkeeperError::TooManyErrors)
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "2\n");
+        fs::remove_file(&path).ok();
+    }
+    #[test]
+    fn interfaces_before_expression() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let expr = format!("tcp({})", listener.local_addr().unwrap());
+        let mut pk = switched("sleep 30", &Switch::default())
+            .check_expr(Some(expr.parse().unwrap()))
+            .interface("pk-missing0");
+        let events = pk.subscribe();
+        let stop = pk.stop_handle();
+        let supervisor = thread::spawn(move || pk.run());
+        let (finished, before) = wait_for(&events, |event| {
+            matches!(event, Event::CheckFinished { .. })
+        });
+        assert!(matches!(
+            finished,
+            Event::CheckFinished {
+                reachable: false,
+                ..
+            }
+        ));
+        assert!(!before
+            .iter()
+            .any(|event| matches!(event, Event::LeafChecked { .. })));
+        stop.stop();
+        assert!(supervisor.join().unwrap().is_ok());
+    }
+    #[test]
+    fn leaf_probes() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let pk = Pingkeeper::new("true")
+            .hosts(vec![address.clone()])
+            .interface("lo");
+        let credentials = Credentials::new(None, None, None).unwrap();
+        let timeout = Duration::from_secs(1);
+        let leaf_probe = |expr: &str| match expr.parse().unwrap() {
+            CheckExpr::Leaf(leaf) => pk.leaf_probe(&leaf, &credentials),
+            _ => unreachable!(),
+        };
+        let (probe, hosts) = leaf_probe("tcp()");
+        assert_eq!(probe.name(), "tcp");
+        assert_eq!(hosts, vec![address.clone()]);
+        assert!(probe.probe(&hosts[0], timeout).success);
+        let (probe, hosts) = leaf_probe("tcp(1.1.1.1 8.8.8.8)");
+        assert_eq!(probe.name(), "tcp");
+        assert_eq!(hosts, vec!["1.1.1.1", "8.8.8.8"]);
+        let (probe, hosts) = leaf_probe("ping()");
+        assert_eq!(probe.name(), "ping");
+        assert_eq!(hosts, vec![address.clone()]);
+        let (probe, hosts) = leaf_probe("http(http://example.com/)");
+        assert_eq!(probe.name(), "http");
+        assert_eq!(hosts, vec!["http://example.com/"]);
+        // interfaces, not hosts
+        let (probe, hosts) = leaf_probe("interface()");
+        assert_eq!(probe.name(), "interface");
+        assert_eq!(hosts, vec!["lo"]);
+        let (probe, hosts) = leaf_probe("interface(pk-missing0)");
+        assert_eq!(hosts, vec!["pk-missing0"]);
+        assert!(!probe.probe(&hosts[0], timeout).success);
+        // commands get network hosts
+        let (probe, hosts) = leaf_probe(&format!("cmd(test \"$__PK_HOSTS\" = {})", address));
+        assert_eq!(probe.name(), "cmd");
+        assert_eq!(hosts, vec![address.clone()]);
+        assert!(probe.probe(&probe.targets(&hosts)[0], timeout).success);
+        assert!(!probe.probe("other", timeout).success);
+    }
+    #[test]
+    fn degraded_is_down() {
+        // answers without latency are degraded
+        let thresholds = QualityThresholds {
+            p50: Some(Duration::from_millis(10)),
+            ..QualityThresholds::default()
+        };
+        let switch = Switch::default();
+        let mut pk = switched("sleep 0.2", &switch)
+            .edge(true)
+            .quality(thresholds)
+            .samples(1)
+            .degraded_is_down(true);
+        let events = pk.subscribe();
+        let stop = pk.stop_handle();
+        let supervisor = thread::spawn(move || pk.run());
+        wait_for(&events, is_spawned);
+        // degraded is the same outage, not a new one
+        switch.set(true);
+        let (quality, _) = wait_for(&events, |event| {
+            matches!(event, Event::QualityMeasured { .. })
+        });
+        assert!(matches!(
+            quality,
+            Event::QualityMeasured { degraded: true, .. }
+        ));
+        let degraded = events_for(&events, Duration::from_millis(500));
+        assert!(!degraded.iter().any(is_spawned));
+        assert!(!degraded
+            .iter()
+            .any(|event| matches!(event, Event::StateChanged { .. })));
+        switch.set(false);
+        let down = events_for(&events, Duration::from_millis(500));
+        assert!(!down.iter().any(is_spawned));
+        stop.stop();
+        assert!(supervisor.join().unwrap().is_ok());
+    }
+    #[test]
+    fn edge() {
+        let switch = Switch::default();
+        let mut pk = switched("sleep 0.2", &switch).edge(true);
+        let events = pk.subscribe();
+        let stop = pk.stop_handle();
+        let supervisor = thread::spawn(move || pk.run());
+        // once per outage
+        let outage = events_for(&events, Duration::from_millis(800));
+        assert_eq!(outage.iter().filter(|event| is_spawned(event)).count(), 1);
+        switch.set(true);
+        wait_for(&events, |event| matches!(event, Event::Recovered { .. }));
+        let up = events_for(&events, Duration::from_millis(500));
+        assert!(!up.iter().any(is_spawned));
+        // next outage
+        switch.set(false);
+        wait_for(&events, is_spawned);
+        stop.stop();
+        assert!(supervisor.join().unwrap().is_ok());
+    }
+    #[test]
+    fn edge_repeat_and_recovery() {
+        let path = std::env::temp_dir().join(format!("pk-test-recovery-{}", process::id()));
+        fs::remove_file(&path).ok();
+        let switch = Switch::default();
+        let mut pk = switched("test -n \"$__PK_OUTAGE_SECONDS\" && sleep 0.2", &switch)
+            .edge(true)
+            .repeat_every(Some(Duration::from_millis(300)))
+            .recovery_cmd(Some(format!(
+                "echo \"$__PK_OUTAGE_SECONDS\" > {}",
+                path.display()
+            )));
+        let events = pk.subscribe();
+        let stop = pk.stop_handle();
+        let supervisor = thread::spawn(move || pk.run());
+        // again after a while, with the outage duration
+        let outage = events_for(&events, Duration::from_millis(1000));
+        assert!(outage.iter().filter(|event| is_spawned(event)).count() >= 2);
+        assert!(outage.iter().all(|event| match event {
+            Event::ChildExited { status, .. } => status.is_some_and(|status| status.success()),
+            _ => true,
+        }));
+        // recovery command, once
+        switch.set(true);
+        wait_for(&events, |event| matches!(event, Event::Recovered { .. }));
+        let up = events_for(&events, Duration::from_millis(500));
+        assert!(!up
+            .iter()
+            .any(|event| matches!(event, Event::Recovered { .. })));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "0\n");
+        fs::remove_file(&path).ok();
+        stop.stop();
+        assert!(supervisor.join().unwrap().is_ok());
+    }
+    #[test]
+    fn network_probe_priority() {
+        let hook = Credentials::default();
+        let pk = Pingkeeper::new("echo");
+        assert_eq!(pk.network_probe(&hook).name(), "tcp");
+        let pk = pk.use_ping(true);
+        assert_eq!(pk.network_probe(&hook).name(), "ping");
+        let pk = pk.check_cmd(Some("echo"));
+        assert_eq!(pk.network_probe(&hook).name(), "cmd");
+        let pk = pk.probe(TcpProbe::new(None));
+        assert_eq!(pk.network_probe(&hook).name(), "tcp");
+    }
+    #[test]
     fn spawn_controller_test() {
         let mut executor = Executor::new(String::from("cat"));
         executor.set_signal("SIGTERM");
         // Should spawn
-        match spawn_controller(&mut executor, &None, true) {
+        match spawn_controller(&mut executor, &None, true, SpawnReason::Start) {
             Ok(SpawnOk::SpawnOk(_)) => {}
-            res => panic!(format!("Invalid result {:?}", res)),
+            res => panic!("Invalid result {:?}", res),
         }
         // Should kill
-        match spawn_controller(&mut executor, &None, true) {
+        match spawn_controller(&mut executor, &None, true, SpawnReason::Start) {
             Ok(SpawnOk::KillOk(_)) => {}
-            res => panic!(format!("Invalid result {:?}", res)),
+            res => panic!("Invalid result {:?}", res),
         }
         // Should spawn
-        match spawn_controller(&mut executor, &None, true) {
+        match spawn_controller(&mut executor, &None, true, SpawnReason::Start) {
             Ok(SpawnOk::SpawnOk(_)) => {}
-            res => panic!(format!("Invalid result {:?}", res)),
+            res => panic!("Invalid result {:?}", res),
         }
     }
 }
@@ -0,0 +1,366 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::{close, getppid, Pid};
+use regex::Regex;
+use std::fs;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::command::Command;
+
+/// How to find an already running process to adopt instead of spawning one
+#[derive(Debug, Clone, Default)]
+pub struct Adoption {
+    pidfile: Option<PathBuf>,
+    pid: Option<u32>,
+    cmdline: Option<Regex>,
+}
+
+impl Adoption {
+    /// Reads the pid from a file, pingkeeper writes there the pid of each spawned process
+    pub fn pidfile<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.pidfile = Some(path.into());
+        self
+    }
+    /// Adopts this pid, over the pidfile
+    pub fn pid(mut self, pid: u32) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+    /// Command line of the process must match instead of the command, or the first one matching if there is no pid
+    pub fn cmdline(mut self, cmdline: Regex) -> Self {
+        self.cmdline = Some(cmdline);
+        self
+    }
+    /// Nothing to adopt?
+    pub fn is_empty(&self) -> bool {
+        self.pidfile.is_none() && self.pid.is_none() && self.cmdline.is_none()
+    }
+    /// Gets the pidfile, if any
+    pub fn get_pidfile(&self) -> Option<&Path> {
+        self.pidfile.as_deref()
+    }
+    /// Finds a running process to adopt, running this command unless a command line is set
+    ///
+    /// A stale pid may belong to any other process by now, its command line must match.
+    pub fn find(&self, command: &Command) -> Option<u32> {
+        let pid = self
+            .pid
+            .or_else(|| self.pidfile.as_ref().and_then(read_pidfile));
+        let is_match = |pid: u32| match (cmdline(pid), &self.cmdline) {
+            (Some(cmdline), Some(regex)) => regex.is_match(&cmdline),
+            (Some(cmdline), None) => is_command(&cmdline, command),
+            (None, _) => false,
+        };
+        match (pid, &self.cmdline) {
+            (Some(pid), _) => Some(pid).filter(|pid| is_running(*pid) && is_match(*pid)),
+            (None, Some(regex)) => find_cmdline(regex),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Adopted process, monitored and signaled through a pidfd where available
+///
+/// A pidfd refers to the process itself, so a reused pid is never signaled.
+#[derive(Debug)]
+pub struct AdoptedProcess {
+    pid: u32,
+    pidfd: Option<RawFd>,
+}
+
+impl AdoptedProcess {
+    /// Opens a running process, `None` if it is not running
+    pub fn open(pid: u32) -> Option<Self> {
+        if !is_running(pid) {
+            return None;
+        }
+        let process = AdoptedProcess {
+            pid,
+            pidfd: pidfd_open(pid),
+        };
+        // still running once opened
+        Some(process).filter(AdoptedProcess::is_running)
+    }
+    /// Gets the pid
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+    /// Is it running? A pidfd is readable once the process exits
+    pub fn is_running(&self) -> bool {
+        match self.pidfd {
+            Some(fd) => {
+                let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+                matches!(poll(&mut fds, 0), Ok(0))
+            }
+            None => is_running(self.pid),
+        }
+    }
+    /// Waits for it to exit, returns false if it is still running after the timeout
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.is_running() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+    /// Sends a signal, or checks that it could be sent without one
+    pub fn signal(&self, signal: Option<Signal>) -> nix::Result<()> {
+        match self.pidfd {
+            Some(fd) => pidfd_send_signal(fd, signal),
+            None => kill(Pid::from_raw(self.pid as i32), signal),
+        }
+    }
+}
+
+impl Drop for AdoptedProcess {
+    fn drop(&mut self) {
+        if let Some(fd) = self.pidfd {
+            close(fd).ok();
+        }
+    }
+}
+
+/// Opens a pidfd, close-on-exec; Linux 5.3 or later
+#[cfg(target_os = "linux")]
+fn pidfd_open(pid: u32) -> Option<RawFd> {
+    use nix::libc;
+    // SAFETY: no pointers, the fd is owned by the caller
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+    if fd < 0 {
+        None
+    } else {
+        Some(fd as RawFd)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pidfd_open(_pid: u32) -> Option<RawFd> {
+    None
+}
+
+/// Sends a signal through a pidfd
+#[cfg(target_os = "linux")]
+fn pidfd_send_signal(fd: RawFd, signal: Option<Signal>) -> nix::Result<()> {
+    use nix::libc;
+    let signal = signal.map_or(0, |signal| signal as libc::c_int);
+    // SAFETY: a null siginfo is sent as from kill
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            fd,
+            signal,
+            std::ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+    Errno::result(res).map(drop)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pidfd_send_signal(_fd: RawFd, _signal: Option<Signal>) -> nix::Result<()> {
+    Err(Errno::ENOSYS)
+}
+
+/// Does the process exist, and is not a zombie?
+///
+/// A process of another user exists if it cannot be signaled.
+pub fn is_running(pid: u32) -> bool {
+    if pid == 0 || pid > i32::MAX as u32 {
+        return false;
+    }
+    match kill(Pid::from_raw(pid as i32), None) {
+        Ok(()) | Err(Errno::EPERM) => !is_zombie(pid),
+        Err(_) => false,
+    }
+}
+
+/// Is the process a zombie, according to `/proc/<pid>/stat`?
+fn is_zombie(pid: u32) -> bool {
+    fs::read_to_string(format!("/proc/{}/stat", pid))
+        .ok()
+        .and_then(|stat| parse_state(&stat))
+        .is_some_and(|state| state == 'Z')
+}
+
+/// Parses the state of `/proc/<pid>/stat`, after the process name
+fn parse_state(stat: &str) -> Option<char> {
+    stat[stat.rfind(')')? + 1..].trim_start().chars().next()
+}
+
+/// Reads the first line of a pidfile
+fn read_pidfile<P: AsRef<Path>>(path: P) -> Option<u32> {
+    fs::read_to_string(path)
+        .ok()?
+        .lines()
+        .next()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Writes a pid to a pidfile
+pub fn write_pidfile<P: AsRef<Path>>(path: P, pid: u32) -> std::io::Result<()> {
+    fs::write(path, format!("{}\n", pid))
+}
+
+/// Removes a pidfile if it has this pid, another pingkeeper may have written it since
+pub fn remove_pidfile<P: AsRef<Path>>(path: P, pid: u32) -> std::io::Result<()> {
+    match read_pidfile(&path) {
+        Some(read) if read == pid => fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+/// Is it the command line of the command, run by a shell or by itself?
+///
+/// Shells run a simple command line with exec, quotes are gone then.
+fn is_command(cmdline: &str, command: &Command) -> bool {
+    match command {
+        Command::Shell(cmd) => {
+            let unquoted: Vec<String> = cmd
+                .split_whitespace()
+                .map(|word| word.replace(['"', '\''], ""))
+                .collect();
+            cmdline == format!("/bin/sh -c {}", cmd) || cmdline == unquoted.join(" ")
+        }
+        Command::Argv(argv) => cmdline == argv.join(" "),
+    }
+}
+
+/// Command line of a process, arguments joined with spaces
+fn cmdline(pid: u32) -> Option<String> {
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let args: Vec<String> = cmdline
+        .split(|byte| *byte == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    if args.is_empty() {
+        None
+    } else {
+        Some(args.join(" "))
+    }
+}
+
+/// Lowest pid whose command line matches, but pingkeeper and its parent
+fn find_cmdline(regex: &Regex) -> Option<u32> {
+    let own = [process::id(), getppid().as_raw() as u32];
+    let mut pids: Vec<u32> = fs::read_dir("/proc")
+        .ok()?
+        .filter_map(Result::ok)
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .filter(|pid| !own.contains(pid))
+        .collect();
+    pids.sort_unstable();
+    pids.into_iter()
+        .filter(|pid| cmdline(*pid).is_some_and(|cmdline| regex.is_match(&cmdline)))
+        .find(|pid| is_running(*pid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn state() {
+        assert_eq!(parse_state("42 (a) b) S 1 42"), Some('S'));
+        assert_eq!(parse_state("42 (openvpn) Z 1"), Some('Z'));
+        assert_eq!(parse_state(""), None);
+    }
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn find() {
+        let mut child = process::Command::new("sleep")
+            .arg("5.4321")
+            .spawn()
+            .unwrap();
+        // exec'd, command line is sleep's
+        sleep(Duration::from_millis(50));
+        let pid = child.id();
+        assert!(is_running(pid));
+        assert!(!is_running(0));
+        // pid, running the command or matching the command line
+        let command = Command::from("sleep 5.4321");
+        let openvpn = Command::from("openvpn");
+        assert_eq!(Adoption::default().pid(pid).find(&command), Some(pid));
+        assert_eq!(Adoption::default().pid(pid).find(&openvpn), None);
+        let sleeping = Regex::new("^sleep 5\\.4321$").unwrap();
+        let adoption = Adoption::default().pid(pid).cmdline(sleeping.clone());
+        assert_eq!(adoption.find(&openvpn), Some(pid));
+        let other = Regex::new("^openvpn").unwrap();
+        let adoption = Adoption::default().pid(pid).cmdline(other);
+        assert_eq!(adoption.find(&command), None);
+        // command line
+        assert_eq!(
+            Adoption::default().cmdline(sleeping).find(&openvpn),
+            Some(pid)
+        );
+        // pidfile, stale for another command
+        let path = env::temp_dir().join(format!("pingkeeper-adopt-{}.pid", process::id()));
+        write_pidfile(&path, pid).unwrap();
+        assert_eq!(Adoption::default().pidfile(&path).find(&command), Some(pid));
+        assert_eq!(Adoption::default().pidfile(&path).find(&openvpn), None);
+        // through a pidfd
+        let adopted = AdoptedProcess::open(pid).unwrap();
+        assert!(adopted.is_running());
+        assert!(adopted.signal(None).is_ok());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert!(!adopted.is_running());
+        assert!(AdoptedProcess::open(pid).is_none());
+        assert!(!is_running(pid));
+        assert_eq!(Adoption::default().pidfile(&path).find(&command), None);
+        // only its own pid
+        remove_pidfile(&path, pid + 1).unwrap();
+        assert!(path.exists());
+        remove_pidfile(&path, pid).unwrap();
+        assert!(!path.exists());
+        assert!(Adoption::default().is_empty());
+    }
+    #[test]
+    fn command_line() {
+        let shell = Command::from("openvpn --config \"my vpn.conf\"");
+        assert!(is_command(
+            "/bin/sh -c openvpn --config \"my vpn.conf\"",
+            &shell
+        ));
+        // exec'd by the shell
+        assert!(is_command("openvpn --config my vpn.conf", &shell));
+        assert!(!is_command("openvpn", &shell));
+        let argv = Command::from(vec!["openvpn", "--config", "my vpn.conf"]);
+        assert!(is_command("openvpn --config my vpn.conf", &argv));
+        assert!(!is_command(
+            "/bin/sh -c openvpn --config my vpn.conf",
+            &argv
+        ));
+    }
+}
@@ -0,0 +1,109 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::process;
+
+/// Command to run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Command line run through `/bin/sh -c`
+    Shell(String),
+    /// Program and arguments, executed directly without a shell
+    Argv(Vec<String>),
+}
+
+impl Command {
+    /// Is there anything to run?
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Command::Shell(cmd) => cmd.trim().is_empty(),
+            Command::Argv(argv) => argv.is_empty() || argv[0].is_empty(),
+        }
+    }
+    /// Builds a process to run this command
+    pub fn to_process(&self) -> process::Command {
+        match self {
+            Command::Shell(cmd) => {
+                let mut process = process::Command::new("/bin/sh");
+                process.arg("-c").arg(cmd);
+                process
+            }
+            Command::Argv(argv) => {
+                let mut process = process::Command::new(argv.first().map_or("", String::as_str));
+                process.args(argv.iter().skip(1));
+                process
+            }
+        }
+    }
+}
+
+impl From<String> for Command {
+    fn from(cmd: String) -> Self {
+        Command::Shell(cmd)
+    }
+}
+impl From<&str> for Command {
+    fn from(cmd: &str) -> Self {
+        Command::Shell(String::from(cmd))
+    }
+}
+impl<S: Into<String>> From<Vec<S>> for Command {
+    fn from(argv: Vec<S>) -> Self {
+        Command::Argv(argv.into_iter().map(Into::into).collect())
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Shell(cmd) => write!(f, "{}", cmd),
+            Command::Argv(argv) => write!(f, "{}", argv.join(" ")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_empty() {
+        assert!(Command::from(" ").is_empty());
+        assert!(Command::from(Vec::<String>::new()).is_empty());
+        assert!(!Command::from("echo").is_empty());
+        assert!(!Command::from(vec!["echo"]).is_empty());
+    }
+    #[test]
+    fn argv_without_shell() {
+        // a shell would expand the variable
+        let output = Command::from(vec!["echo", "$HOME", "a b"])
+            .to_process()
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "$HOME a b\n");
+    }
+    #[test]
+    fn shell() {
+        let output = Command::from("echo \"a  b\" | tr a c")
+            .to_process()
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "c  b\n");
+    }
+}
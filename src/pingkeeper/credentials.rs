@@ -0,0 +1,253 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+#[cfg(target_os = "linux")]
+use nix::unistd::{getgrouplist, setgroups};
+use nix::unistd::{setgid, setuid, Gid, Group, Uid, User};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process;
+
+/// Credentials errors
+#[derive(Debug, PartialEq, Eq)]
+pub enum CredentialsError {
+    UnknownUser,
+    UnknownGroup,
+    UnknownCapability,
+    NotPermitted,
+}
+
+/// User, group and supplementary groups to run a process as
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Credentials {
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+    groups: Option<Vec<Gid>>,
+}
+
+// Public
+impl Credentials {
+    /// Resolves user, group and supplementary groups, by name or id
+    ///
+    /// Group defaults to the user's primary group, supplementary groups to the user's groups.
+    /// Supplementary groups are only supported on Linux.
+    pub fn new(
+        user: Option<&str>,
+        group: Option<&str>,
+        groups: Option<&[String]>,
+    ) -> Result<Self, CredentialsError> {
+        let user = match user {
+            Some(user) => Some(find_user(user)?),
+            None => None,
+        };
+        let gid = match (group, &user) {
+            (Some(group), _) => Some(find_group(group)?),
+            (None, Some((_, Some(user)))) => Some(user.gid),
+            _ => None,
+        };
+        let groups = match (groups, &user, gid) {
+            (Some(groups), _, _) => Some(
+                groups
+                    .iter()
+                    .map(|group| find_group(group))
+                    .collect::<Result<Vec<Gid>, CredentialsError>>()?,
+            ),
+            (None, Some((_, Some(user))), Some(gid)) => user_groups(&user.name, gid),
+            (None, Some(_), Some(gid)) => Some(vec![gid]),
+            _ => None,
+        };
+        Ok(Credentials {
+            uid: user.map(|(uid, _)| uid),
+            gid,
+            groups,
+        })
+    }
+    /// Does not change anything?
+    pub fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none() && self.groups.is_none()
+    }
+    /// Gets user and group ids, if set
+    pub fn owner(&self) -> (Option<Uid>, Option<Gid>) {
+        (self.uid, self.gid)
+    }
+    /// Sets credentials to a process, applied before exec
+    pub fn apply(&self, cmd: &mut process::Command) {
+        if self.is_empty() {
+            return;
+        }
+        let credentials = self.clone();
+        // SAFETY: only async-signal-safe calls, groups are allocated before fork
+        unsafe {
+            cmd.pre_exec(move || credentials.set().map_err(io::Error::from));
+        }
+    }
+}
+
+// Private
+impl Credentials {
+    /// Sets credentials to the current process, groups first and user last
+    fn set(&self) -> nix::Result<()> {
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(groups) = &self.groups {
+                setgroups(groups)?;
+            }
+        }
+        if let Some(gid) = self.gid {
+            setgid(gid)?;
+        }
+        if let Some(uid) = self.uid {
+            setuid(uid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Drops privileges of the current process, keeping some capabilities
+///
+/// Kept capabilities are also ambient, inherited by child processes. Capabilities are only
+/// supported on Linux.
+pub fn drop_privileges(
+    credentials: &Credentials,
+    keep_caps: &[String],
+) -> Result<(), CredentialsError> {
+    #[cfg(target_os = "linux")]
+    {
+        use caps::{CapSet, Capability, CapsHashSet};
+        use std::str::FromStr;
+        let caps = keep_caps
+            .iter()
+            .map(|cap| Capability::from_str(&caps::to_canonical(cap)))
+            .collect::<Result<CapsHashSet, _>>()
+            .map_err(|_| CredentialsError::UnknownCapability)?;
+        if !caps.is_empty() {
+            caps::securebits::set_keepcaps(true).map_err(|_| CredentialsError::NotPermitted)?;
+        }
+        credentials
+            .set()
+            .map_err(|_| CredentialsError::NotPermitted)?;
+        if !caps.is_empty() {
+            for set in &[CapSet::Effective, CapSet::Permitted, CapSet::Inheritable] {
+                caps::set(None, *set, &caps).map_err(|_| CredentialsError::NotPermitted)?;
+            }
+            for cap in caps {
+                caps::raise(None, CapSet::Ambient, cap)
+                    .map_err(|_| CredentialsError::NotPermitted)?;
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        if !keep_caps.is_empty() {
+            return Err(CredentialsError::UnknownCapability);
+        }
+        credentials
+            .set()
+            .map_err(|_| CredentialsError::NotPermitted)
+    }
+}
+
+/// Finds a user by name or uid, uid may not have an entry
+fn find_user(user: &str) -> Result<(Uid, Option<User>), CredentialsError> {
+    if let Ok(Some(found)) = User::from_name(user) {
+        return Ok((found.uid, Some(found)));
+    }
+    match user.parse::<u32>() {
+        Ok(uid) => {
+            let uid = Uid::from_raw(uid);
+            Ok((uid, User::from_uid(uid).ok().flatten()))
+        }
+        Err(_) => Err(CredentialsError::UnknownUser),
+    }
+}
+
+/// Finds a group by name or gid
+fn find_group(group: &str) -> Result<Gid, CredentialsError> {
+    if let Ok(Some(found)) = Group::from_name(group) {
+        return Ok(found.gid);
+    }
+    group
+        .parse::<u32>()
+        .map(Gid::from_raw)
+        .map_err(|_| CredentialsError::UnknownGroup)
+}
+
+/// Gets groups of a user
+#[cfg(target_os = "linux")]
+fn user_groups(name: &str, gid: Gid) -> Option<Vec<Gid>> {
+    let name = CString::new(name).ok()?;
+    getgrouplist(&name, gid).ok()
+}
+#[cfg(not(target_os = "linux"))]
+fn user_groups(_name: &str, _gid: Gid) -> Option<Vec<Gid>> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty() {
+        let credentials = Credentials::new(None, None, None).unwrap();
+        assert!(credentials.is_empty());
+        assert_eq!(credentials, Credentials::default());
+    }
+    #[test]
+    fn by_name_or_id() {
+        let by_name = Credentials::new(Some("root"), None, None).unwrap();
+        let by_id = Credentials::new(Some("0"), None, None).unwrap();
+        assert_eq!(by_name, by_id);
+        assert_eq!(by_name.uid, Some(Uid::from_raw(0)));
+        assert_eq!(by_name.gid, Some(Gid::from_raw(0)));
+        let group = Credentials::new(None, Some("0"), Some(&[String::from("0")])).unwrap();
+        assert_eq!(group.uid, None);
+        assert_eq!(group.groups, Some(vec![Gid::from_raw(0)]));
+    }
+    #[test]
+    fn unknown() {
+        assert_eq!(
+            Credentials::new(Some("__pk__test__user__"), None, None),
+            Err(CredentialsError::UnknownUser)
+        );
+        assert_eq!(
+            Credentials::new(None, Some("__pk__test__group__"), None),
+            Err(CredentialsError::UnknownGroup)
+        );
+    }
+    #[test]
+    fn apply() {
+        // Only root can switch to another user
+        let user = if Uid::current().is_root() {
+            String::from("nobody")
+        } else {
+            Uid::current().to_string()
+        };
+        let credentials = Credentials::new(Some(&user), None, None).unwrap();
+        let mut cmd = process::Command::new("id");
+        cmd.arg("-u");
+        credentials.apply(&mut cmd);
+        let output = cmd.output().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            credentials.uid.unwrap().to_string()
+        );
+    }
+}
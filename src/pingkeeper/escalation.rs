@@ -0,0 +1,211 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use super::command::Command;
+
+/// What to do on a failed check, at a step of an escalation ladder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EscalationAction {
+    /// Run or restart child process, as without a ladder
+    Restart,
+    /// Run a hook command, it gets `__PK_ESCALATION_STEP`
+    Hook(Command),
+    /// Give up with too many errors
+    Exit,
+}
+impl fmt::Display for EscalationAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EscalationAction::Restart => write!(f, "restart"),
+            EscalationAction::Hook(_) => write!(f, "hook"),
+            EscalationAction::Exit => write!(f, "exit"),
+        }
+    }
+}
+
+/// A step of an escalation ladder, tried this many times: restarts, hook runs or failed checks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EscalationStep {
+    pub action: EscalationAction,
+    pub attempts: usize,
+}
+
+impl EscalationStep {
+    pub fn new(action: EscalationAction, attempts: usize) -> Self {
+        EscalationStep {
+            action,
+            attempts: attempts.max(1),
+        }
+    }
+}
+
+/// Parses `restart[:N]`, `hook:N:COMMAND` or `exit`
+impl FromStr for EscalationStep {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, ':');
+        let attempts = |n: Option<&str>| match n {
+            None => Ok(1),
+            Some(n) => n
+                .parse()
+                .ok()
+                .filter(|n| *n > 0)
+                .ok_or_else(|| format!("invalid attempts: `{}`", n)),
+        };
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("restart"), n, None) => {
+                Ok(EscalationStep::new(EscalationAction::Restart, attempts(n)?))
+            }
+            (Some("hook"), n @ Some(_), Some(cmd)) if !cmd.is_empty() => Ok(EscalationStep::new(
+                EscalationAction::Hook(Command::from(cmd)),
+                attempts(n)?,
+            )),
+            (Some("exit"), None, None) => Ok(EscalationStep::new(EscalationAction::Exit, 1)),
+            _ => Err(format!("invalid escalation step: `{}`", s)),
+        }
+    }
+}
+
+/// Ladder of steps for failed checks, the last one repeats
+///
+/// It goes back to the first step once network is reachable for a while.
+#[derive(Debug, Clone)]
+pub struct Escalation {
+    steps: Vec<EscalationStep>,
+    stable_for: Duration,
+    step: usize,
+    attempts: usize,
+    failures: usize,
+    stable_since: Option<Instant>,
+}
+
+impl Escalation {
+    pub fn new(steps: Vec<EscalationStep>, stable_for: Duration) -> Self {
+        Escalation {
+            steps,
+            stable_for,
+            step: 0,
+            attempts: 0,
+            failures: 0,
+            stable_since: None,
+        }
+    }
+    /// Climbs the ladder on a failed check, returns step number, attempt and action
+    pub fn escalate(&mut self) -> Option<(usize, usize, EscalationAction)> {
+        self.stable_since = None;
+        let attempts = self.steps.get(self.step)?.attempts;
+        if self.attempts >= attempts && self.step + 1 < self.steps.len() {
+            self.step += 1;
+            self.attempts = 0;
+        }
+        self.attempts += 1;
+        self.failures += 1;
+        let action = self.steps[self.step].action.clone();
+        Some((self.step + 1, self.attempts, action))
+    }
+    /// Action of the next failed check, without climbing the ladder
+    pub fn next_action(&self) -> Option<&EscalationAction> {
+        let step = self.steps.get(self.step)?;
+        if self.attempts >= step.attempts && self.step + 1 < self.steps.len() {
+            Some(&self.steps[self.step + 1].action)
+        } else {
+            Some(&step.action)
+        }
+    }
+    /// Network is reachable, returns true if the ladder was reset after being stable
+    pub fn stabilize(&mut self) -> bool {
+        let since = *self.stable_since.get_or_insert_with(Instant::now);
+        if self.failures > 0 && since.elapsed() >= self.stable_for {
+            self.step = 0;
+            self.attempts = 0;
+            self.failures = 0;
+            true
+        } else {
+            false
+        }
+    }
+    /// Failed checks since the last reset
+    pub fn failures(&self) -> usize {
+        self.failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_steps() {
+        let step: EscalationStep = "restart:2".parse().unwrap();
+        assert_eq!(step, EscalationStep::new(EscalationAction::Restart, 2));
+        let step: EscalationStep = "restart".parse().unwrap();
+        assert_eq!(step.attempts, 1);
+        let step: EscalationStep = "hook:1:ip link set tun0 down; ip link set tun0 up"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            step.action,
+            EscalationAction::Hook(Command::from("ip link set tun0 down; ip link set tun0 up"))
+        );
+        assert_eq!(
+            "exit".parse(),
+            Ok(EscalationStep::new(EscalationAction::Exit, 1))
+        );
+        assert!("hook:reboot".parse::<EscalationStep>().is_err());
+        assert!("restart:0".parse::<EscalationStep>().is_err());
+        assert!("reboot".parse::<EscalationStep>().is_err());
+    }
+    #[test]
+    fn ladder() {
+        let hook = EscalationAction::Hook(Command::from("reset"));
+        let mut ladder = Escalation::new(
+            vec![
+                EscalationStep::new(EscalationAction::Restart, 2),
+                EscalationStep::new(hook.clone(), 1),
+            ],
+            Duration::from_secs(0),
+        );
+        assert_eq!(ladder.escalate(), Some((1, 1, EscalationAction::Restart)));
+        assert_eq!(ladder.escalate(), Some((1, 2, EscalationAction::Restart)));
+        assert_eq!(ladder.escalate(), Some((2, 1, hook.clone())));
+        // last step repeats
+        assert_eq!(ladder.escalate(), Some((2, 2, hook)));
+        assert_eq!(ladder.failures(), 4);
+        assert!(ladder.stabilize());
+        assert!(!ladder.stabilize());
+        assert_eq!(ladder.escalate(), Some((1, 1, EscalationAction::Restart)));
+        assert_eq!(
+            Escalation::new(vec![], Duration::from_secs(0)).escalate(),
+            None
+        );
+    }
+    #[test]
+    fn stable_for() {
+        let mut ladder = Escalation::new(
+            vec![EscalationStep::new(EscalationAction::Restart, 1)],
+            Duration::from_secs(60),
+        );
+        ladder.escalate();
+        assert!(!ladder.stabilize());
+        assert_eq!(ladder.failures(), 1);
+    }
+}
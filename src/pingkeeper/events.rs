@@ -0,0 +1,157 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use super::policy::RestartPolicy;
+
+use super::escalation::EscalationAction;
+use super::netlink::NetworkChange;
+use super::network_monitor::{FailureLayer, ProbeOutcome, Quality};
+use super::rules::RuleAction;
+use super::usage::{Metric, Usage};
+
+/// Network state, as seen by the latest check
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum NetworkState {
+    Unknown,
+    Reachable,
+    Unreachable,
+    /// Reachable, but over latency or loss thresholds
+    Degraded,
+}
+impl fmt::Display for NetworkState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetworkState::Unknown => write!(f, "unknown"),
+            NetworkState::Reachable => write!(f, "reachable"),
+            NetworkState::Unreachable => write!(f, "unreachable"),
+            NetworkState::Degraded => write!(f, "degraded"),
+        }
+    }
+}
+
+/// Events emitted by the main loop
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event {
+    /// Network changed, a check follows
+    NetworkChanged { changes: Vec<NetworkChange> },
+    /// Network check started
+    CheckStarted,
+    /// Network check finished, with results per host
+    CheckFinished {
+        reachable: bool,
+        results: Vec<ProbeOutcome>,
+    },
+    /// Quality of a reachable network measured
+    QualityMeasured {
+        target: String,
+        quality: Quality,
+        degraded: bool,
+    },
+    /// Leaf of the check expression checked
+    LeafChecked { leaf: String, passed: bool },
+    /// Host group checked, with results per host
+    GroupChecked {
+        group: String,
+        reachable: bool,
+        results: Vec<ProbeOutcome>,
+    },
+    /// Host group state changed
+    GroupStateChanged {
+        group: String,
+        from: NetworkState,
+        to: NetworkState,
+    },
+    /// Escalation ladder step taken on a failed check
+    Escalated {
+        step: usize,
+        attempt: usize,
+        action: EscalationAction,
+    },
+    /// Escalation ladder back to its first step, network is stable
+    EscalationReset,
+    /// Failed network check diagnosed
+    Diagnosed { layer: FailureLayer },
+    /// Network state changed
+    StateChanged {
+        from: NetworkState,
+        to: NetworkState,
+    },
+    /// Network is reachable again after an outage, edge mode
+    Recovered { outage: Duration },
+    /// Child process spawned
+    ChildSpawned { pid: u32 },
+    /// Already running process adopted as child process
+    ChildAdopted { pid: u32 },
+    /// Child process could not be spawned or died on boot
+    SpawnFailed,
+    /// Child process is ready
+    ChildReady { pid: u32 },
+    /// Child process was not ready in time, it will be restarted
+    ReadyTimeout { pid: u32 },
+    /// Child process exited, with status if known, `oom` if killed by the OOM killer of its cgroup
+    ChildExited {
+        pid: u32,
+        status: Option<ExitStatus>,
+        oom: bool,
+    },
+    /// Child process exceeded a resource usage threshold, it will be restarted
+    ThresholdExceeded {
+        pid: u32,
+        metric: Metric,
+        usage: Usage,
+    },
+    /// Liveness probe of child process done, with failures in a row
+    LivenessChecked {
+        pid: u32,
+        outcome: ProbeOutcome,
+        failures: usize,
+    },
+    /// Liveness probe of child process failed too many times, it will be restarted
+    LivenessFailed { pid: u32, failures: usize },
+    /// Output of child process matched a rule
+    OutputMatched {
+        pid: u32,
+        rule: String,
+        action: RuleAction,
+        line: String,
+    },
+    /// Child process exited with a fatal status, Pingkeeper is giving up
+    FatalExit {
+        pid: u32,
+        status: Option<ExitStatus>,
+    },
+    /// Child process exited and is not restarted, Pingkeeper is done
+    NotRestarted { pid: u32, policy: RestartPolicy },
+    /// Child process status could not be read
+    ChildError { error: String },
+    /// Kill signal or custom kill command sent to child process
+    KillSent { pid: u32 },
+    /// Kill signal or custom kill command failed
+    KillFailed { pid: u32 },
+    /// Too many errors in a row, Pingkeeper is giving up
+    GaveUp { errors: usize },
+}
+
+/// Event consumer
+pub type Subscriber = Box<dyn Fn(&Event) + Send>;
@@ -19,9 +19,20 @@
 use nix::sys::signal::{kill, Signal};
 use nix::unistd::Pid;
 use std::fmt;
+use std::fs;
 use std::io::{self};
+use std::path::{Path, PathBuf};
 use std::process;
 use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use super::adopt::AdoptedProcess;
+use super::command::Command;
+use super::credentials::Credentials;
+use super::limits::{Cgroup, Limits};
+use super::output::{OutputCapture, OutputLine};
+use super::policy::exit_env;
 
 /// Executor errors
 #[derive(Debug, PartialEq, Eq)]
@@ -31,42 +42,146 @@ pub enum ExecutorError {
 }
 impl fmt::Display for ExecutorError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self)
+        write!(f, "{:?}", self)
+    }
+}
+
+/// Why a child process is spawned, available to it as `__PK_REASON`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SpawnReason {
+    /// First run
+    Start,
+    /// Network check failed
+    NetworkUnreachable,
+    /// Network check succeeded, gate mode
+    NetworkReachable,
+    /// Previous child process exited
+    ChildExited,
+    /// Previous child process was killed by the OOM killer of its cgroup
+    OutOfMemory,
+    /// Previous child process exceeded a resource usage threshold
+    ThresholdExceeded,
+    /// Liveness probe of previous child process failed
+    LivenessFailed,
+    /// Previous child process was not ready in time
+    NotReady,
+    /// Output of previous child process matched a rule
+    OutputMatched,
+}
+impl fmt::Display for SpawnReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpawnReason::Start => write!(f, "start"),
+            SpawnReason::NetworkUnreachable => write!(f, "network_unreachable"),
+            SpawnReason::NetworkReachable => write!(f, "network_reachable"),
+            SpawnReason::ChildExited => write!(f, "child_exited"),
+            SpawnReason::OutOfMemory => write!(f, "out_of_memory"),
+            SpawnReason::ThresholdExceeded => write!(f, "threshold_exceeded"),
+            SpawnReason::LivenessFailed => write!(f, "liveness_failed"),
+            SpawnReason::NotReady => write!(f, "not_ready"),
+            SpawnReason::OutputMatched => write!(f, "output_matched"),
+        }
     }
 }
 
 /// Executor
 #[derive(Debug)]
 pub struct Executor {
-    command: String,
+    command: Command,
     signal: Option<Signal>,
+    kill_timeout: Duration,
+    current_dir: Option<PathBuf>,
+    env_clear: bool,
+    env_remove: Vec<String>,
+    env: Vec<(String, String)>,
+    spawn_env: Vec<(String, String)>,
+    credentials: Credentials,
+    hook_credentials: Credentials,
+    limits: Limits,
+    cgroup: Option<Cgroup>,
+    capture: bool,
+    output: Option<OutputCapture>,
+    context: Vec<(String, String)>,
+    spawns: usize,
     child: Option<process::Child>,
+    adopted: Option<AdoptedProcess>,
+    exit_status: Option<process::ExitStatus>,
     error: Option<io::Error>,
 }
 
 // Public impl
 impl Executor {
     /// Creates a new Executor instance
-    pub fn new(command: String) -> Self {
+    pub fn new<C: Into<Command>>(command: C) -> Self {
         Self {
-            command,
+            command: command.into(),
             signal: Some(Signal::SIGINT),
+            kill_timeout: Duration::from_secs(10),
+            current_dir: None,
+            env_clear: false,
+            env_remove: Vec::new(),
+            env: Vec::new(),
+            spawn_env: Vec::new(),
+            credentials: Credentials::default(),
+            hook_credentials: Credentials::default(),
+            limits: Limits::default(),
+            cgroup: None,
+            capture: false,
+            output: None,
+            context: Vec::new(),
+            spawns: 0,
             child: None,
+            adopted: None,
+            exit_status: None,
             error: None,
         }
     }
     /// Spawns a child process
-    pub fn spawn(&mut self, quiet: bool) -> bool {
-        let mut cmd = process::Command::new("/bin/sh");
-        cmd.arg("-c").arg(&self.command);
-        if quiet {
+    ///
+    /// Child process gets `__PK_RESTARTS`, number of previous spawns, `__PK_REASON`,
+    /// and `__PK_EXIT_STATUS` and `__PK_EXIT_SIGNAL` of the previous one.
+    pub fn spawn(&mut self, quiet: bool, reason: SpawnReason) -> bool {
+        let mut cmd = self.command.to_process();
+        if let Some(dir) = &self.current_dir {
+            cmd.current_dir(dir);
+        }
+        if self.env_clear {
+            cmd.env_clear();
+        }
+        for key in &self.env_remove {
+            cmd.env_remove(key);
+        }
+        for (key, value) in &self.env {
+            cmd.env(key, value);
+        }
+        cmd.env("__PK_RESTARTS", self.spawns.to_string())
+            .env("__PK_REASON", reason.to_string());
+        if self.exit_status.is_some() {
+            cmd.envs(exit_env(self.exit_status));
+        }
+        cmd.envs(self.context.iter().cloned());
+        cmd.envs(self.spawn_env.drain(..));
+        // cgroup and limits before dropping privileges
+        if let Some(cgroup) = &mut self.cgroup {
+            cgroup.apply(&mut cmd);
+        }
+        self.limits.apply(&mut cmd);
+        self.credentials.apply(&mut cmd);
+        if quiet || self.capture {
             cmd.stdout(process::Stdio::piped())
                 .stderr(process::Stdio::piped());
         }
         match cmd.spawn() {
-            Ok(child) => {
+            Ok(mut child) => {
+                if self.capture {
+                    self.output = Some(OutputCapture::start(&mut child, quiet));
+                }
                 self.child = Some(child);
+                self.adopted = None;
+                self.exit_status = None;
                 self.error = None;
+                self.spawns += 1;
                 true
             }
             Err(error) => {
@@ -76,45 +191,101 @@ impl Executor {
             }
         }
     }
+    /// Adopts an already running process instead of spawning one, if it is running
+    ///
+    /// It is not a child: it is monitored through a pidfd, or `kill(pid, 0)` without one, and its
+    /// exit status is unknown.
+    pub fn adopt(&mut self, pid: u32) -> bool {
+        let adopted = match AdoptedProcess::open(pid) {
+            Some(adopted) => adopted,
+            None => return false,
+        };
+        self.adopted = Some(adopted);
+        self.child = None;
+        self.output = None;
+        self.exit_status = None;
+        self.error = None;
+        true
+    }
     /// Sends kill signal to child process, if any
     pub fn kill(&mut self) -> Result<(), ExecutorError> {
+        if let Some(adopted) = &self.adopted {
+            if adopted.signal(self.signal).is_err() {
+                return Err(ExecutorError::SignalNotSent);
+            }
+            // Not a child, it cannot be waited for; killed if it ignores the signal
+            if !adopted.wait_timeout(self.kill_timeout)
+                && (adopted.signal(Some(Signal::SIGKILL)).is_err()
+                    || !adopted.wait_timeout(self.kill_timeout))
+            {
+                return Err(ExecutorError::SignalNotSent);
+            }
+            self.adopted = None;
+        }
         if let Some(child) = &mut self.child {
             if kill(Pid::from_raw(child.id() as i32), self.signal).is_err() {
                 return Err(ExecutorError::SignalNotSent);
             }
-            child.wait().ok();
+            self.exit_status = child.wait().ok();
         }
         Ok(())
     }
-    /// Custom kill command
-    pub fn kill_custom_cmd(&mut self, cmd: &str) -> Result<(), ExecutorError> {
+    /// Custom kill command, it gets `__PK_PID`, `__PK_EXIT_STATUS` and `__PK_EXIT_SIGNAL`
+    pub fn kill_custom_cmd<C: Into<Command>>(&mut self, cmd: C) -> Result<(), ExecutorError> {
         let pid_env;
         if let Some(pid) = self.get_pid() {
             pid_env = format!("{}", pid);
         } else {
             pid_env = String::new();
         }
-        let success = process::Command::new("/bin/sh")
-            .arg("-c")
-            .arg(cmd)
-            .env("__PK_PID", pid_env)
+        let mut cmd = cmd.into().to_process();
+        cmd.env("__PK_PID", pid_env)
+            .envs(exit_env(self.exit_status))
+            .envs(self.context.iter().cloned());
+        self.hook_credentials.apply(&mut cmd);
+        let success = cmd
             .output()
-            .expect("No shell?")
-            .status
-            .success();
+            .map(|output| output.status.success())
+            .unwrap_or(false);
         if success {
             Ok(())
         } else {
             Err(ExecutorError::SignalNotSent)
         }
     }
+    /// Runs a hook command in background, with `__PK_PID` and more environment variables
+    pub fn run_hook<C: Into<Command>>(&mut self, cmd: C, env: &[(&str, &str)]) {
+        let pid = self
+            .get_pid()
+            .map(|pid| pid.to_string())
+            .unwrap_or_default();
+        let mut cmd = cmd.into().to_process();
+        cmd.env("__PK_PID", pid)
+            .envs(exit_env(self.exit_status))
+            .envs(self.context.iter().cloned());
+        for (key, value) in env {
+            cmd.env(key, value);
+        }
+        self.hook_credentials.apply(&mut cmd);
+        thread::spawn(move || cmd.output());
+    }
     /// Is child process alive?
     pub fn is_alive(&mut self) -> Result<bool, ExecutorError> {
+        if let Some(adopted) = &self.adopted {
+            if adopted.is_running() {
+                return Ok(true);
+            }
+            self.adopted = None;
+            return Ok(false);
+        }
         if let Some(child) = &mut self.child {
             let status = child.try_wait();
             return match status {
                 Ok(None) => Ok(true),
-                Ok(_) => Ok(false),
+                Ok(status) => {
+                    self.exit_status = status;
+                    Ok(false)
+                }
                 Err(_) => Err(ExecutorError::StatusError),
             };
         }
@@ -124,6 +295,9 @@ impl Executor {
     pub fn get_pid(&mut self) -> Option<u32> {
         if let Ok(is_alive) = self.is_alive() {
             if is_alive {
+                if let Some(adopted) = &self.adopted {
+                    return Some(adopted.pid());
+                }
                 if let Some(child) = &mut self.child {
                     return Some(child.id());
                 }
@@ -131,6 +305,14 @@ impl Executor {
         }
         None
     }
+    /// Gets number of child processes spawned
+    pub fn get_spawns(&self) -> usize {
+        self.spawns
+    }
+    /// Gets exit status of the latest child process, if it has exited
+    pub fn get_exit_status(&self) -> Option<process::ExitStatus> {
+        self.exit_status
+    }
     /// Sets kill signal
     pub fn set_signal(&mut self, signal: &str) {
         if let Ok(signal) = Signal::from_str(signal) {
@@ -139,11 +321,121 @@ impl Executor {
             self.signal = None;
         };
     }
+    /// Sets kill signal, `None` only checks that the process exists
+    pub fn set_kill_signal(&mut self, signal: Option<Signal>) {
+        self.signal = signal;
+    }
+    /// Sets working directory of child process
+    pub fn set_current_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.current_dir = Some(dir.into());
+    }
+    /// Starts child process with an empty environment, besides variables set
+    pub fn set_env_clear(&mut self, clear: bool) {
+        self.env_clear = clear;
+    }
+    /// Removes an inherited environment variable from child process
+    pub fn remove_env(&mut self, key: &str) {
+        self.env_remove.push(String::from(key));
+    }
+    /// Sets user and groups of child process
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = credentials;
+    }
+    /// Sets time for an adopted process to exit after the kill signal, before `SIGKILL`
+    pub fn set_kill_timeout(&mut self, timeout: Duration) {
+        self.kill_timeout = timeout;
+    }
+    /// Sets user and groups of custom kill command
+    pub fn set_hook_credentials(&mut self, credentials: Credentials) {
+        self.hook_credentials = credentials;
+    }
+    /// Sets resource limits of child process
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    /// Runs child process in its own cgroup
+    pub fn set_cgroup(&mut self, cgroup: Cgroup) {
+        self.cgroup = Some(cgroup);
+    }
+    /// Was the latest child process killed by the OOM killer of its cgroup?
+    pub fn is_oom_killed(&self) -> bool {
+        self.cgroup.as_ref().is_some_and(Cgroup::is_oom_killed)
+    }
+    /// Captures output of child process line by line, echoed unless quiet
+    pub fn set_capture(&mut self, capture: bool) {
+        self.capture = capture;
+    }
+    /// Gets output lines of child process since last call, if captured
+    pub fn read_output(&self) -> Vec<OutputLine> {
+        self.output
+            .as_ref()
+            .map_or_else(Vec::new, OutputCapture::lines)
+    }
+    /// Gets output lines of an exited child process, until its streams are closed or a short while
+    pub fn read_final_output(&self) -> Vec<OutputLine> {
+        self.output
+            .as_ref()
+            .map_or_else(Vec::new, |output| output.drain(Duration::from_millis(100)))
+    }
+    /// Sets a context variable for child process and hooks, replacing its previous value
+    pub fn set_context(&mut self, key: &str, value: &str) {
+        match self.context.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = String::from(value),
+            None => self.context.push((String::from(key), String::from(value))),
+        }
+    }
+    /// Sets an environment variable for child process
+    pub fn set_env(&mut self, key: &str, value: &str) {
+        match self.env.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = String::from(value),
+            None => self.env.push((String::from(key), String::from(value))),
+        }
+    }
+    /// Sets an environment variable for the next child process only
+    pub fn set_spawn_env(&mut self, key: &str, value: &str) {
+        self.spawn_env
+            .push((String::from(key), String::from(value)));
+    }
+    /// Sets environment variables from a file with `KEY=VALUE` lines
+    pub fn load_env_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let content = fs::read_to_string(path)?;
+        self.env.extend(parse_env_file(&content));
+        Ok(())
+    }
+}
+
+/// Parses `KEY=VALUE` lines, skipping comments and an optional `export`, unquoting values
+fn parse_env_file(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.trim_start_matches("export "))
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next()?.trim();
+            let value = parts.next()?.trim();
+            let value = if value.len() >= 2
+                && ((value.starts_with('"') && value.ends_with('"'))
+                    || (value.starts_with('\'') && value.ends_with('\'')))
+            {
+                &value[1..value.len() - 1]
+            } else {
+                value
+            };
+            if key.is_empty() {
+                None
+            } else {
+                Some((String::from(key), String::from(value)))
+            }
+        })
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::os::unix::process::ExitStatusExt;
     use std::thread::sleep;
     use std::time::Duration;
 
@@ -154,25 +446,25 @@ mod tests {
     #[test]
     fn spawn() {
         let mut executor = Executor::new(String::from("echo"));
-        assert!(executor.spawn(true));
+        assert!(executor.spawn(true, SpawnReason::Start));
     }
     #[test]
     fn is_alive() {
         // A command that never ends
         let mut executor = Executor::new(String::from("cat"));
-        assert!(executor.spawn(true));
+        assert!(executor.spawn(true, SpawnReason::Start));
         assert!(executor.is_alive().is_ok());
         assert!(executor.is_alive().unwrap());
         // A command that ends
         let mut executor = Executor::new(String::from("echo"));
-        assert!(executor.spawn(true));
+        assert!(executor.spawn(true, SpawnReason::Start));
         sleep(Duration::from_millis(10));
         assert!(executor.is_alive().is_ok());
         assert!(!executor.is_alive().unwrap());
         // A command killed
         let mut executor = Executor::new(String::from("cat"));
         executor.set_signal("SIGTERM");
-        assert!(executor.spawn(true));
+        assert!(executor.spawn(true, SpawnReason::Start));
         assert!(executor.kill().is_ok());
         assert!(executor.is_alive().is_ok());
         assert!(!executor.is_alive().unwrap());
@@ -182,7 +474,7 @@ mod tests {
         // A command that never ends
         let mut executor = Executor::new(String::from("cat"));
         executor.set_signal("SIGTERM");
-        assert!(executor.spawn(true));
+        assert!(executor.spawn(true, SpawnReason::Start));
         assert!(executor.is_alive().is_ok());
         assert!(executor.is_alive().unwrap());
         assert!(executor.kill().is_ok());
@@ -193,7 +485,7 @@ mod tests {
         // A command that never ends
         let mut executor = Executor::new(String::from("echo"));
         executor.set_signal("SIGTERM");
-        assert!(executor.spawn(true));
+        assert!(executor.spawn(true, SpawnReason::Start));
         assert!(executor.kill_custom_cmd("echo").is_ok());
         assert!(executor
             .kill_custom_cmd("cat __pingkeep__test__command__")
@@ -203,18 +495,142 @@ mod tests {
     fn get_pid() {
         // A command that never ends
         let mut executor = Executor::new(String::from("cat"));
-        assert!(executor.spawn(true));
+        assert!(executor.spawn(true, SpawnReason::Start));
         assert!(executor.get_pid().is_some());
         // A command that ends
         let mut executor = Executor::new(String::from("echo"));
-        assert!(executor.spawn(true));
+        assert!(executor.spawn(true, SpawnReason::Start));
         assert!(executor.get_pid().is_some());
         // A command that does not exist
         let mut executor = Executor::new(String::from("__pingkeep__test__command__"));
-        assert!(executor.spawn(true));
+        assert!(executor.spawn(true, SpawnReason::Start));
         assert!(executor.get_pid().is_some());
     }
     #[test]
+    fn adopt() {
+        let mut other = process::Command::new("cat")
+            .stdin(process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pid = other.id();
+        let mut executor = Executor::new(String::from("echo"));
+        executor.set_signal("SIGTERM");
+        assert!(executor.adopt(pid));
+        assert!(executor.is_alive().unwrap());
+        assert_eq!(executor.get_pid(), Some(pid));
+        assert_eq!(executor.get_spawns(), 0);
+        // Reaped by its parent, here the test
+        let waiter = thread::spawn(move || other.wait());
+        assert!(executor.kill().is_ok());
+        assert!(waiter.join().unwrap().is_ok());
+        assert!(!executor.is_alive().unwrap());
+        assert!(executor.get_exit_status().is_none());
+        assert!(!executor.adopt(pid));
+    }
+    #[test]
+    fn adopt_kill_timeout() {
+        let mut other = process::Command::new("sleep").arg("30").spawn().unwrap();
+        let mut executor = Executor::new(String::from("echo"));
+        // no signal, as if ignored
+        executor.set_signal("none");
+        executor.set_kill_timeout(Duration::from_millis(100));
+        assert!(executor.adopt(other.id()));
+        let waiter = thread::spawn(move || other.wait());
+        assert!(executor.kill().is_ok());
+        let status = waiter.join().unwrap().unwrap();
+        assert_eq!(status.signal(), Some(Signal::SIGKILL as i32));
+        assert!(!executor.is_alive().unwrap());
+    }
+    #[test]
+    fn get_exit_status() {
+        let mut executor = Executor::new(String::from("exit 3"));
+        assert!(executor.spawn(true, SpawnReason::Start));
+        assert!(executor.get_exit_status().is_none());
+        sleep(Duration::from_millis(100));
+        assert!(!executor.is_alive().unwrap());
+        assert_eq!(executor.get_exit_status().unwrap().code(), Some(3));
+    }
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn argv_pid() {
+        // Without shell, pid is the program itself
+        let mut executor = Executor::new(vec!["sleep", "5"]);
+        executor.set_signal("SIGTERM");
+        assert!(executor.spawn(true, SpawnReason::Start));
+        sleep(Duration::from_millis(50));
+        let pid = executor.get_pid().unwrap();
+        let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).unwrap();
+        assert_eq!(comm.trim(), "sleep");
+        assert!(executor.kill().is_ok());
+        assert_eq!(
+            executor.get_exit_status().unwrap().signal(),
+            Some(Signal::SIGTERM as i32)
+        );
+    }
+    #[test]
+    fn env_and_dir() {
+        let mut executor = Executor::new(
+            "test \"$PWD\" = /tmp && test \"$PK_TEST\" = 1 && test -z \"$HOME\" \
+             && test \"$__PK_RESTARTS\" = 1 && test \"$__PK_REASON\" = network_unreachable",
+        );
+        executor.set_current_dir("/tmp");
+        executor.set_env("PK_TEST", "1");
+        executor.remove_env("HOME");
+        assert!(executor.spawn(true, SpawnReason::Start));
+        sleep(Duration::from_millis(100));
+        assert!(!executor.is_alive().unwrap());
+        assert!(!executor.get_exit_status().unwrap().success());
+        assert!(executor.spawn(true, SpawnReason::NetworkUnreachable));
+        sleep(Duration::from_millis(100));
+        assert!(!executor.is_alive().unwrap());
+        assert!(executor.get_exit_status().unwrap().success());
+        assert_eq!(executor.get_spawns(), 2);
+    }
+    #[test]
+    fn env_clear() {
+        let mut executor = Executor::new(vec!["/usr/bin/env"]);
+        executor.set_env_clear(true);
+        executor.set_env("PK_TEST", "1");
+        assert!(executor.spawn(true, SpawnReason::Start));
+        let output = executor.child.take().unwrap().wait_with_output().unwrap();
+        let mut vars: Vec<String> = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(String::from)
+            .collect();
+        vars.sort();
+        assert_eq!(
+            vars,
+            vec!["PK_TEST=1", "__PK_REASON=start", "__PK_RESTARTS=0"]
+        );
+    }
+    #[test]
+    fn spawn_env() {
+        let mut executor = Executor::new("test \"$PK_TEST\" = 1");
+        executor.set_spawn_env("PK_TEST", "1");
+        assert!(executor.spawn(true, SpawnReason::Start));
+        sleep(Duration::from_millis(100));
+        assert!(!executor.is_alive().unwrap());
+        assert!(executor.get_exit_status().unwrap().success());
+        // next one does not get it
+        assert!(executor.spawn(true, SpawnReason::ChildExited));
+        sleep(Duration::from_millis(100));
+        assert!(!executor.is_alive().unwrap());
+        assert!(!executor.get_exit_status().unwrap().success());
+    }
+    #[test]
+    fn env_file() {
+        let content = "# comment\n\nA=1\nexport B=\"two words\"\nC='3'\nD\n=5\nE=a=b\n";
+        assert_eq!(
+            parse_env_file(content),
+            vec![
+                (String::from("A"), String::from("1")),
+                (String::from("B"), String::from("two words")),
+                (String::from("C"), String::from("3")),
+                (String::from("E"), String::from("a=b")),
+            ]
+        );
+    }
+    #[test]
     fn set_signal() {
         let mut executor = Executor::new(String::from("echo"));
         executor.set_signal("SIGTERM");
@@ -0,0 +1,273 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Probe of a leaf in a check expression
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LeafKind {
+    Tcp,
+    Ping,
+    Http,
+    Cmd,
+    Interface,
+}
+impl fmt::Display for LeafKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LeafKind::Tcp => write!(f, "tcp"),
+            LeafKind::Ping => write!(f, "ping"),
+            LeafKind::Http => write!(f, "http"),
+            LeafKind::Cmd => write!(f, "cmd"),
+            LeafKind::Interface => write!(f, "interface"),
+        }
+    }
+}
+impl FromStr for LeafKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(LeafKind::Tcp),
+            "ping" => Ok(LeafKind::Ping),
+            "http" => Ok(LeafKind::Http),
+            "cmd" => Ok(LeafKind::Cmd),
+            "interface" => Ok(LeafKind::Interface),
+            _ => Err(format!("unknown check: `{}`", s)),
+        }
+    }
+}
+
+/// A single check, like `tcp(1.1.1.1 8.8.8.8)`, passes if one of its targets answers
+///
+/// Arguments are hosts, urls or interfaces, space separated, or a command for `cmd`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckLeaf {
+    pub kind: LeafKind,
+    pub args: String,
+}
+impl fmt::Display for CheckLeaf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({})", self.kind, self.args)
+    }
+}
+
+/// Boolean expression of checks, with `and`, `or`, `not` and parentheses
+///
+/// Like `tcp(1.1.1.1 8.8.8.8) and http(http://example.com/) or cmd(test -f canary)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CheckExpr {
+    Leaf(CheckLeaf),
+    Not(Box<CheckExpr>),
+    And(Box<CheckExpr>, Box<CheckExpr>),
+    Or(Box<CheckExpr>, Box<CheckExpr>),
+}
+
+impl CheckExpr {
+    /// Evaluates with short-circuiting, checking leaves from left to right
+    pub fn evaluate<F: FnMut(&CheckLeaf) -> bool>(&self, check: &mut F) -> bool {
+        match self {
+            CheckExpr::Leaf(leaf) => check(leaf),
+            CheckExpr::Not(expr) => !expr.evaluate(check),
+            CheckExpr::And(left, right) => left.evaluate(check) && right.evaluate(check),
+            CheckExpr::Or(left, right) => left.evaluate(check) || right.evaluate(check),
+        }
+    }
+}
+
+impl FromStr for CheckExpr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { input: s, pos: 0 };
+        let expr = parser.or()?;
+        parser.skip_whitespace();
+        if parser.pos < s.len() {
+            return Err(format!("unexpected `{}`", &s[parser.pos..]));
+        }
+        Ok(expr)
+    }
+}
+
+/// Recursive descent parser: `not` binds tighter than `and`, and `and` than `or`
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn or(&mut self) -> Result<CheckExpr, String> {
+        let mut expr = self.and()?;
+        while self.keyword("or") {
+            expr = CheckExpr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+    fn and(&mut self) -> Result<CheckExpr, String> {
+        let mut expr = self.unary()?;
+        while self.keyword("and") {
+            expr = CheckExpr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+    fn unary(&mut self) -> Result<CheckExpr, String> {
+        if self.keyword("not") {
+            return Ok(CheckExpr::Not(Box::new(self.unary()?)));
+        }
+        self.skip_whitespace();
+        if self.rest().starts_with('(') {
+            self.pos += 1;
+            let expr = self.or()?;
+            self.skip_whitespace();
+            if !self.rest().starts_with(')') {
+                return Err(String::from("missing `)`"));
+            }
+            self.pos += 1;
+            return Ok(expr);
+        }
+        self.leaf()
+    }
+    /// `kind(args)`, args may have balanced parentheses
+    fn leaf(&mut self) -> Result<CheckExpr, String> {
+        let word = self.word();
+        let kind = word.parse()?;
+        if !self.rest().starts_with('(') {
+            return Err(format!("missing `(` after `{}`", word));
+        }
+        let start = self.pos + 1;
+        let mut depth = 0;
+        for (i, c) in self.rest().char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                let args = self.input[start..self.pos + i].trim().to_string();
+                if args.is_empty() && (kind == LeafKind::Http || kind == LeafKind::Cmd) {
+                    return Err(format!("missing arguments of `{}`", word));
+                }
+                self.pos += i + 1;
+                return Ok(CheckExpr::Leaf(CheckLeaf { kind, args }));
+            }
+        }
+        Err(format!("missing `)` after `{}`", word))
+    }
+    /// Consumes a keyword, if next
+    fn keyword(&mut self, keyword: &str) -> bool {
+        self.skip_whitespace();
+        let pos = self.pos;
+        if self.word() == keyword {
+            true
+        } else {
+            self.pos = pos;
+            false
+        }
+    }
+    fn word(&mut self) -> &'a str {
+        self.skip_whitespace();
+        let input = self.input;
+        let len = self
+            .rest()
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or_else(|| self.rest().len());
+        self.pos += len;
+        &input[self.pos - len..self.pos]
+    }
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(kind: LeafKind, args: &str) -> CheckExpr {
+        CheckExpr::Leaf(CheckLeaf {
+            kind,
+            args: String::from(args),
+        })
+    }
+
+    #[test]
+    fn parse() {
+        let expr: CheckExpr =
+            "tcp(1.1.1.1 8.8.8.8) and http(http://example.com/) or cmd(test -f (canary))"
+                .parse()
+                .unwrap();
+        let tcp = leaf(LeafKind::Tcp, "1.1.1.1 8.8.8.8");
+        let http = leaf(LeafKind::Http, "http://example.com/");
+        let cmd = leaf(LeafKind::Cmd, "test -f (canary)");
+        assert_eq!(
+            expr,
+            CheckExpr::Or(
+                Box::new(CheckExpr::And(
+                    Box::new(tcp.clone()),
+                    Box::new(http.clone())
+                )),
+                Box::new(cmd.clone())
+            )
+        );
+        let expr: CheckExpr = "not (tcp() or ping())and interface(tun0)".parse().unwrap();
+        assert_eq!(
+            expr,
+            CheckExpr::And(
+                Box::new(CheckExpr::Not(Box::new(CheckExpr::Or(
+                    Box::new(leaf(LeafKind::Tcp, "")),
+                    Box::new(leaf(LeafKind::Ping, ""))
+                )))),
+                Box::new(leaf(LeafKind::Interface, "tun0"))
+            )
+        );
+        for invalid in [
+            "",
+            "tcp",
+            "tcp(",
+            "udp(1.1.1.1)",
+            "tcp() and",
+            "(tcp()",
+            "tcp() ping()",
+            "http()",
+            "cmd( )",
+        ]
+        .iter()
+        {
+            assert!(invalid.parse::<CheckExpr>().is_err(), "{}", invalid);
+        }
+    }
+    #[test]
+    fn short_circuit() {
+        let expr: CheckExpr = "(cmd(a) and cmd(b)) or cmd(c) or cmd(d)".parse().unwrap();
+        let mut checked = vec![];
+        let result = expr.evaluate(&mut |leaf: &CheckLeaf| {
+            checked.push(leaf.args.clone());
+            leaf.args == "c"
+        });
+        assert!(result);
+        assert_eq!(checked, vec!["a", "c"]);
+        let leaf = CheckLeaf {
+            kind: LeafKind::Ping,
+            args: String::from("@gateway"),
+        };
+        assert_eq!(leaf.to_string(), "ping(@gateway)");
+    }
+}
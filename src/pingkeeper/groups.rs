@@ -0,0 +1,213 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::command::Command;
+use super::network_monitor::{Probe, ProbeOutcome};
+
+/// What to do when a host group goes down
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupAction {
+    /// Network is unreachable for command too
+    Command,
+    /// Run a hook command, it gets `__PK_GROUP`
+    Hook(Command),
+    /// Only log it
+    Log,
+}
+impl fmt::Display for GroupAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GroupAction::Command => write!(f, "command"),
+            GroupAction::Hook(_) => write!(f, "hook"),
+            GroupAction::Log => write!(f, "log"),
+        }
+    }
+}
+impl FromStr for GroupAction {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "command" => Ok(GroupAction::Command),
+            "log" => Ok(GroupAction::Log),
+            _ => match s.strip_prefix("hook:") {
+                Some(cmd) if !cmd.is_empty() => Ok(GroupAction::Hook(Command::from(cmd))),
+                _ => Err(format!("unknown group action: `{}`", s)),
+            },
+        }
+    }
+}
+
+/// A named group of hosts, checked with its own probe and quorum
+#[derive(Clone)]
+pub struct HostGroup {
+    pub name: String,
+    pub hosts: Vec<String>,
+    /// Probe of the network check if none
+    pub probe: Option<Arc<dyn Probe>>,
+    /// Hosts that must answer for the group to be up
+    pub quorum: usize,
+    pub action: GroupAction,
+}
+
+impl HostGroup {
+    /// Creates a group of hosts checked with the network probe and a quorum of one
+    pub fn new<S: Into<String>>(name: S, hosts: Vec<String>, action: GroupAction) -> Self {
+        HostGroup {
+            name: name.into(),
+            hosts,
+            probe: None,
+            quorum: 1,
+            action,
+        }
+    }
+    /// Sets the probe of this group
+    pub fn probe<P: Probe + 'static>(mut self, probe: P) -> Self {
+        self.probe = Some(Arc::new(probe));
+        self
+    }
+    /// Sets how many hosts must answer
+    pub fn quorum(mut self, quorum: usize) -> Self {
+        self.quorum = quorum.max(1);
+        self
+    }
+    /// Is the group up with these outcomes?
+    pub fn is_up(&self, outcomes: &[ProbeOutcome]) -> bool {
+        outcomes.iter().filter(|outcome| outcome.success).count() >= self.quorum
+    }
+}
+
+/// Host group as given in command line, probes by name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GroupSpec {
+    pub name: String,
+    pub hosts: Vec<String>,
+    /// `tcp`, `ping` or `http`
+    pub probe: Option<String>,
+    pub quorum: usize,
+    pub action: GroupAction,
+}
+
+/// Parses `name=NAME,hosts=HOSTS[,probe=PROBE][,quorum=N][,action=ACTION]`
+///
+/// Hosts are space separated, action goes last and may contain commas.
+impl FromStr for GroupSpec {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut hosts: Option<Vec<String>> = None;
+        let mut probe = None;
+        let mut quorum = 1;
+        let mut action = GroupAction::Log;
+        let mut rest = s;
+        while !rest.is_empty() {
+            let field = if rest.starts_with("action=") {
+                std::mem::take(&mut rest)
+            } else {
+                let mut parts = rest.splitn(2, ',');
+                let field = parts.next().unwrap_or_default();
+                rest = parts.next().unwrap_or_default();
+                field
+            };
+            let mut parts = field.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("name"), Some(value)) if !value.is_empty() => name = Some(value),
+                (Some("hosts"), Some(value)) => {
+                    hosts = Some(value.split_whitespace().map(String::from).collect())
+                }
+                (Some("probe"), Some(value @ "tcp"))
+                | (Some("probe"), Some(value @ "ping"))
+                | (Some("probe"), Some(value @ "http")) => probe = Some(String::from(value)),
+                (Some("quorum"), Some(value)) => {
+                    quorum = value
+                        .parse()
+                        .ok()
+                        .filter(|quorum| *quorum > 0)
+                        .ok_or_else(|| format!("invalid quorum: `{}`", value))?
+                }
+                (Some("action"), Some(value)) => action = value.parse()?,
+                _ => return Err(format!("invalid group field: `{}`", field)),
+            }
+        }
+        // tokens are resolved on every check, to any number of hosts
+        let is_fixed = |hosts: &[String]| !hosts.iter().any(|host| host.starts_with('@'));
+        match (name, hosts) {
+            (Some(_), Some(hosts))
+                if !hosts.is_empty() && is_fixed(&hosts) && quorum > hosts.len() =>
+            {
+                Err(format!(
+                    "quorum {} is more than the {} hosts: `{}`",
+                    quorum,
+                    hosts.len(),
+                    s
+                ))
+            }
+            (Some(name), Some(hosts)) if !hosts.is_empty() => Ok(GroupSpec {
+                name: String::from(name),
+                hosts,
+                probe,
+                quorum,
+                action,
+            }),
+            _ => Err(format!("group needs a name and hosts: `{}`", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_spec() {
+        let spec: GroupSpec =
+            "name=internet,hosts=1.1.1.1 8.8.8.8,quorum=2,action=hook:reset --all,now"
+                .parse()
+                .unwrap();
+        assert_eq!(spec.name, "internet");
+        assert_eq!(spec.hosts, vec!["1.1.1.1", "8.8.8.8"]);
+        assert_eq!(spec.probe, None);
+        assert_eq!(spec.quorum, 2);
+        assert_eq!(
+            spec.action,
+            GroupAction::Hook(Command::from("reset --all,now"))
+        );
+        let spec: GroupSpec = "name=lan,hosts=@gateway,probe=ping".parse().unwrap();
+        assert_eq!(spec.probe, Some(String::from("ping")));
+        assert_eq!(spec.action, GroupAction::Log);
+        assert!("name=lan".parse::<GroupSpec>().is_err());
+        assert!("name=lan,hosts=a,probe=icmp".parse::<GroupSpec>().is_err());
+        assert!("name=lan,hosts=a,quorum=0".parse::<GroupSpec>().is_err());
+        assert!("name=lan,hosts=a b,quorum=3".parse::<GroupSpec>().is_err());
+        assert!("name=lan,hosts=@dns,quorum=2".parse::<GroupSpec>().is_ok());
+        assert!("name=lan,hosts=a,action=restart"
+            .parse::<GroupSpec>()
+            .is_err());
+    }
+    #[test]
+    fn quorum() {
+        let group = HostGroup::new("lan", vec![], GroupAction::Log).quorum(2);
+        let ok = ProbeOutcome::success("a", None);
+        let ko = ProbeOutcome::failure("b", "no");
+        assert!(!group.is_up(&[ok.clone(), ko.clone()]));
+        assert!(group.is_up(&[ok.clone(), ko, ok]));
+    }
+}
@@ -0,0 +1,203 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use nix::fcntl::{open, OFlag};
+use nix::sys::resource::{setrlimit, Resource};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, write};
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// Resource limits of a process, soft and hard
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum number of open files, `RLIMIT_NOFILE`
+    pub nofile: Option<u64>,
+    /// Maximum virtual memory in bytes, `RLIMIT_AS`
+    pub address_space: Option<u64>,
+    /// Maximum CPU time in seconds, `RLIMIT_CPU`
+    pub cpu: Option<u64>,
+}
+
+impl Limits {
+    /// Does not limit anything?
+    pub fn is_empty(&self) -> bool {
+        self.nofile.is_none() && self.address_space.is_none() && self.cpu.is_none()
+    }
+    /// Sets limits to a process, applied before exec
+    pub fn apply(&self, cmd: &mut process::Command) {
+        if self.is_empty() {
+            return;
+        }
+        let limits = *self;
+        // SAFETY: setrlimit is async-signal-safe
+        unsafe {
+            cmd.pre_exec(move || limits.set().map_err(io::Error::from));
+        }
+    }
+    /// Sets limits to the current process
+    fn set(&self) -> nix::Result<()> {
+        for (resource, limit) in &[
+            (Resource::RLIMIT_NOFILE, self.nofile),
+            (Resource::RLIMIT_AS, self.address_space),
+            (Resource::RLIMIT_CPU, self.cpu),
+        ] {
+            if let Some(limit) = limit {
+                setrlimit(*resource, Some(*limit), Some(*limit))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Cgroup v2 of a child process, removed on drop
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+    procs: RawFd,
+    oom_kills: u64,
+}
+
+impl Cgroup {
+    /// Creates a cgroup, optionally limiting memory in bytes and CPU in percent of one CPU
+    pub fn create(path: &Path, memory_max: Option<u64>, cpu_max: Option<u32>) -> io::Result<Self> {
+        // enable controllers for children of parent, may be enabled already
+        if let Some(parent) = path.parent() {
+            fs::write(parent.join("cgroup.subtree_control"), "+memory +cpu").ok();
+        }
+        fs::create_dir_all(path)?;
+        if let Some(bytes) = memory_max {
+            fs::write(path.join("memory.max"), bytes.to_string())?;
+        }
+        if let Some(percent) = cpu_max {
+            fs::write(
+                path.join("cpu.max"),
+                format!("{} 100000", u64::from(percent) * 1000),
+            )?;
+        }
+        // opened now, migration permissions are checked against the opener, not the child
+        let procs = open(
+            &path.join("cgroup.procs"),
+            OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .map_err(io::Error::from)?;
+        let mut cgroup = Cgroup {
+            path: path.to_path_buf(),
+            procs,
+            oom_kills: 0,
+        };
+        cgroup.oom_kills = cgroup.read_oom_kills();
+        Ok(cgroup)
+    }
+    /// Moves a process into this cgroup before exec, so none of its children escape
+    ///
+    /// It works after dropping privileges, `cgroup.procs` was opened on creation.
+    pub fn apply(&mut self, cmd: &mut process::Command) {
+        self.oom_kills = self.read_oom_kills();
+        let procs = self.procs;
+        // SAFETY: write is async-signal-safe
+        unsafe {
+            cmd.pre_exec(move || {
+                write(procs, b"0")?;
+                Ok(())
+            });
+        }
+    }
+    /// Has the OOM killer killed a process of this cgroup since the latest `apply`?
+    pub fn is_oom_killed(&self) -> bool {
+        self.read_oom_kills() > self.oom_kills
+    }
+    /// Reads `oom_kill` counter from `memory.events`
+    fn read_oom_kills(&self) -> u64 {
+        fs::read_to_string(self.path.join("memory.events"))
+            .ok()
+            .and_then(|events| {
+                events
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("oom_kill "))
+                    .find_map(|count| count.trim().parse().ok())
+            })
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        close(self.procs).ok();
+        // only empty cgroups can be removed
+        fs::remove_dir(&self.path).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::credentials::Credentials;
+    use super::*;
+    use nix::unistd::Uid;
+    use std::env;
+
+    #[test]
+    fn limits() {
+        let limits = Limits {
+            nofile: Some(42),
+            address_space: None,
+            cpu: Some(7),
+        };
+        let mut cmd = process::Command::new("/bin/sh");
+        cmd.arg("-c").arg("ulimit -n; ulimit -t");
+        limits.apply(&mut cmd);
+        let output = cmd.output().unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "42\n7\n");
+    }
+    #[test]
+    fn cgroup_files() {
+        // a plain directory stands for the cgroup filesystem
+        let path = env::temp_dir().join(format!("pk-test-cgroup-{}", process::id()));
+        assert!(Cgroup::create(&path, None, None).is_err());
+        fs::write(path.join("cgroup.procs"), "").unwrap();
+        let mut cgroup = Cgroup::create(&path, Some(64 * 1024 * 1024), Some(50)).unwrap();
+        assert_eq!(
+            fs::read_to_string(path.join("memory.max")).unwrap(),
+            "67108864"
+        );
+        assert_eq!(
+            fs::read_to_string(path.join("cpu.max")).unwrap(),
+            "50000 100000"
+        );
+        assert!(!cgroup.is_oom_killed());
+        fs::write(path.join("memory.events"), "oom 1\noom_kill 1\n").unwrap();
+        assert!(cgroup.is_oom_killed());
+        // written by the child before exec, even as another user
+        let mut cmd = process::Command::new("true");
+        cgroup.apply(&mut cmd);
+        if Uid::current().is_root() {
+            Credentials::new(Some("nobody"), None, None)
+                .unwrap()
+                .apply(&mut cmd);
+        }
+        assert!(cmd.status().unwrap().success());
+        assert_eq!(fs::read_to_string(path.join("cgroup.procs")).unwrap(), "0");
+        drop(cgroup);
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::command::Command;
+use super::credentials::Credentials;
+use super::network_monitor::{CommandProbe, HttpProbe, Probe, ProbeOutcome, TcpProbe};
+
+/// What to check of the child process
+#[derive(Clone)]
+pub enum LivenessCheck {
+    /// TCP connection to an address with port
+    Tcp(String),
+    /// HTTP `GET` to an `http://` url
+    Http(String),
+    /// Command exit status
+    Command(Command),
+    /// Custom probe and its target
+    Probe(Arc<dyn Probe>, String),
+}
+
+/// Liveness probe of the child process, independent from the network check
+#[derive(Clone)]
+pub struct Liveness {
+    check: LivenessCheck,
+    every: Duration,
+    threshold: usize,
+    grace: Duration,
+}
+
+impl Liveness {
+    /// Creates a liveness probe, every 10 seconds, 3 failures in a row, 30 seconds of grace
+    pub fn new(check: LivenessCheck) -> Self {
+        Liveness {
+            check,
+            every: Duration::from_secs(10),
+            threshold: 3,
+            grace: Duration::from_secs(30),
+        }
+    }
+    /// Sets time between checks
+    pub fn every(mut self, every: Duration) -> Self {
+        self.every = every;
+        self
+    }
+    /// Sets number of failures in a row to restart the child process, at least 1
+    pub fn threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold.max(1);
+        self
+    }
+    /// Sets time without checks after spawning the child process
+    pub fn grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+}
+
+/// Runs a liveness probe over time and counts failures in a row
+pub struct LivenessMonitor {
+    liveness: Liveness,
+    probe: Arc<dyn Probe>,
+    target: String,
+    timeout: Duration,
+    started: Instant,
+    last_check: Option<Instant>,
+    failures: usize,
+}
+
+impl LivenessMonitor {
+    /// Creates a monitor, commands run with these credentials
+    pub fn new(liveness: Liveness, timeout: Duration, credentials: &Credentials) -> Self {
+        let (probe, target): (Arc<dyn Probe>, String) = match &liveness.check {
+            LivenessCheck::Tcp(address) => (Arc::new(TcpProbe::new(None)), address.clone()),
+            LivenessCheck::Http(url) => (Arc::new(HttpProbe::new()), url.clone()),
+            LivenessCheck::Command(cmd) => (
+                Arc::new(
+                    CommandProbe::new(cmd.clone())
+                        .with_credentials(credentials.clone())
+                        .timeout(Some(timeout)),
+                ),
+                String::new(),
+            ),
+            LivenessCheck::Probe(probe, target) => (probe.clone(), target.clone()),
+        };
+        LivenessMonitor {
+            liveness,
+            probe,
+            target,
+            timeout,
+            started: Instant::now(),
+            last_check: None,
+            failures: 0,
+        }
+    }
+    /// Starts over, for a new child process
+    pub fn reset(&mut self) {
+        self.started = Instant::now();
+        self.last_check = None;
+        self.failures = 0;
+    }
+    /// Probes if it is time to, after grace and every interval
+    pub fn check(&mut self) -> Option<ProbeOutcome> {
+        let now = Instant::now();
+        if now.duration_since(self.started) < self.liveness.grace
+            || self
+                .last_check
+                .is_some_and(|last| now.duration_since(last) < self.liveness.every)
+        {
+            return None;
+        }
+        self.last_check = Some(now);
+        let outcome = self.probe.probe(&self.target, self.timeout);
+        if outcome.success {
+            self.failures = 0;
+        } else {
+            self.failures += 1;
+        }
+        Some(outcome)
+    }
+    /// Gets number of failures in a row
+    pub fn failures(&self) -> usize {
+        self.failures
+    }
+    /// Has the probe failed too many times in a row?
+    pub fn is_failed(&self) -> bool {
+        self.failures >= self.liveness.threshold
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(cmd: &str, grace: u64) -> LivenessMonitor {
+        let liveness = Liveness::new(LivenessCheck::Command(Command::from(cmd)))
+            .every(Duration::from_secs(0))
+            .threshold(2)
+            .grace(Duration::from_secs(grace));
+        LivenessMonitor::new(liveness, Duration::from_secs(1), &Credentials::default())
+    }
+
+    #[test]
+    fn threshold() {
+        let mut liveness = monitor("false", 0);
+        assert!(!liveness.check().unwrap().success);
+        assert!(!liveness.is_failed());
+        assert!(!liveness.check().unwrap().success);
+        assert!(liveness.is_failed());
+        assert_eq!(liveness.failures(), 2);
+        liveness.reset();
+        assert_eq!(liveness.failures(), 0);
+        let mut liveness = monitor("true", 0);
+        assert!(liveness.check().unwrap().success);
+        assert!(!liveness.is_failed());
+    }
+    #[test]
+    fn grace() {
+        let mut liveness = monitor("false", 60);
+        assert!(liveness.check().is_none());
+        assert!(!liveness.is_failed());
+    }
+}
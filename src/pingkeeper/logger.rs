@@ -16,7 +16,12 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use std::time::Duration;
+
+use super::events::{Event, NetworkState};
+
 /// Verbosity levels
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd)]
 pub enum LogLevel {
     QUIET = 0,
@@ -39,7 +44,7 @@ impl From<u32> for LogLevel {
 }
 
 /// Returns a logger function that writes message to stdout/stderr if verbosity level allows it
-pub fn logger(verbose: LogLevel) -> impl Fn(LogLevel, String) -> () {
+pub fn logger(verbose: LogLevel) -> impl Fn(LogLevel, String) {
     move |level: LogLevel, message: String| match (level, verbose) {
         (LogLevel::ERROR, v) if v >= LogLevel::ERROR => eprintln!("PK error: {}", message),
         (LogLevel::WARN, v) if v >= LogLevel::WARN => println!("PK warn:  {}", message),
@@ -48,3 +53,230 @@ pub fn logger(verbose: LogLevel) -> impl Fn(LogLevel, String) -> () {
         (_, _) => (),
     }
 }
+
+/// Returns an event consumer that logs events if verbosity level allows it
+pub fn event_logger(verbose: LogLevel) -> impl Fn(&Event) {
+    let logger = logger(verbose);
+    move |event: &Event| match event {
+        Event::NetworkChanged { changes } => {
+            for change in changes {
+                logger(LogLevel::INFO, format!("Network change: {}", change))
+            }
+        }
+        Event::CheckStarted => logger(LogLevel::DEBUG, String::from("Checking network")),
+        Event::CheckFinished { reachable, results } => {
+            for result in results {
+                match (&result.error, result.latency) {
+                    (Some(error), _) => logger(
+                        LogLevel::DEBUG,
+                        format!("Host {} failed -> {}", result.target, error),
+                    ),
+                    (None, Some(latency)) => logger(
+                        LogLevel::DEBUG,
+                        format!("Host {} answered in {:?}", result.target, latency),
+                    ),
+                    (None, None) => {
+                        logger(LogLevel::DEBUG, format!("Host {} answered", result.target))
+                    }
+                }
+                if let Some(stats) = &result.ping {
+                    logger(
+                        LogLevel::DEBUG,
+                        format!("Host {} ping: {}", result.target, stats),
+                    )
+                }
+            }
+            if *reachable {
+                logger(LogLevel::DEBUG, String::from("Network reachable"))
+            } else {
+                logger(LogLevel::WARN, String::from("Network unreachable"))
+            }
+        }
+        Event::QualityMeasured {
+            target,
+            quality,
+            degraded,
+        } => {
+            let millis = |latency: Option<Duration>| {
+                latency.map_or_else(|| String::from("-"), |l| l.as_millis().to_string())
+            };
+            logger(
+                if *degraded {
+                    LogLevel::WARN
+                } else {
+                    LogLevel::DEBUG
+                },
+                format!(
+                    "Quality of {}: p50 {} ms, p95 {} ms, loss {}% of {}{}",
+                    target,
+                    millis(quality.p50),
+                    millis(quality.p95),
+                    quality.loss(),
+                    quality.samples,
+                    if *degraded { ", degraded" } else { "" }
+                ),
+            )
+        }
+        Event::LeafChecked { leaf, passed } => logger(
+            LogLevel::DEBUG,
+            format!(
+                "Check {} {}",
+                leaf,
+                if *passed { "passed" } else { "failed" }
+            ),
+        ),
+        Event::GroupChecked {
+            group,
+            reachable,
+            results,
+        } => {
+            for result in results {
+                let outcome = match (&result.error, result.latency) {
+                    (Some(error), _) => format!("failed -> {}", error),
+                    (None, Some(latency)) => format!("answered in {:?}", latency),
+                    (None, None) => String::from("answered"),
+                };
+                logger(
+                    LogLevel::DEBUG,
+                    format!("Host {} of group {} {}", result.target, group, outcome),
+                )
+            }
+            let state = if *reachable { "up" } else { "down" };
+            logger(LogLevel::DEBUG, format!("Host group {} {}", group, state))
+        }
+        Event::GroupStateChanged { group, from, to } => logger(
+            if *to == NetworkState::Unreachable {
+                LogLevel::WARN
+            } else {
+                LogLevel::INFO
+            },
+            format!("Host group {} changed from {} to {}", group, from, to),
+        ),
+        Event::Escalated {
+            step,
+            attempt,
+            action,
+        } => logger(
+            LogLevel::WARN,
+            format!("Escalation step {} ({}), attempt {}", step, action, attempt),
+        ),
+        Event::EscalationReset => logger(
+            LogLevel::INFO,
+            String::from("Network stable, escalation back to first step"),
+        ),
+        Event::Diagnosed { layer } => {
+            logger(LogLevel::WARN, format!("Network failure layer: {}", layer))
+        }
+        Event::StateChanged { from, to } => logger(
+            LogLevel::INFO,
+            format!("Network state changed from {} to {}", from, to),
+        ),
+        Event::Recovered { outage } => logger(
+            LogLevel::INFO,
+            format!("Network recovered after {} seconds", outage.as_secs()),
+        ),
+        Event::ChildSpawned { pid } => logger(
+            LogLevel::INFO,
+            format!("Child process starting with pid {}", pid),
+        ),
+        Event::ChildAdopted { pid } => logger(
+            LogLevel::INFO,
+            format!("Child process adopted with pid {}", pid),
+        ),
+        Event::SpawnFailed => logger(
+            LogLevel::ERROR,
+            String::from("Child process is dead on boot"),
+        ),
+        Event::ChildReady { pid } => logger(
+            LogLevel::INFO,
+            format!("Child process with pid {} is ready", pid),
+        ),
+        Event::ReadyTimeout { pid } => logger(
+            LogLevel::ERROR,
+            format!("Child process with pid {} is not ready in time", pid),
+        ),
+        Event::ChildExited { pid, oom: true, .. } => logger(
+            LogLevel::WARN,
+            format!("Child process with pid {} is dead, out of memory", pid),
+        ),
+        Event::ChildExited { pid, status, .. } => match status {
+            Some(status) if status.success() => logger(
+                LogLevel::INFO,
+                format!("Child process with pid {} exited", pid),
+            ),
+            Some(status) => logger(
+                LogLevel::WARN,
+                format!("Child process with pid {} is dead, {}", pid, status),
+            ),
+            None => logger(
+                LogLevel::WARN,
+                format!("Child process with pid {} is dead", pid),
+            ),
+        },
+        Event::ThresholdExceeded { pid, metric, usage } => logger(
+            LogLevel::WARN,
+            format!(
+                "Child process with pid {} exceeded {} threshold ({}), restarting",
+                pid, metric, usage
+            ),
+        ),
+        Event::LivenessChecked {
+            pid,
+            outcome,
+            failures,
+        } => match &outcome.error {
+            Some(error) => logger(
+                LogLevel::WARN,
+                format!(
+                    "Liveness of pid {} failed ({} in a row) -> {}",
+                    pid, failures, error
+                ),
+            ),
+            None => logger(LogLevel::DEBUG, format!("Liveness of pid {} ok", pid)),
+        },
+        Event::LivenessFailed { pid, failures } => logger(
+            LogLevel::WARN,
+            format!(
+                "Child process with pid {} is not alive after {} failures, restarting",
+                pid, failures
+            ),
+        ),
+        Event::OutputMatched {
+            pid,
+            rule,
+            action,
+            line,
+        } => logger(
+            LogLevel::WARN,
+            format!(
+                "Output of pid {} matched `{}`, {} -> {}",
+                pid, rule, action, line
+            ),
+        ),
+        Event::FatalExit { pid, status } => logger(
+            LogLevel::ERROR,
+            match status {
+                Some(status) => {
+                    format!("Child process with pid {} fatal {}, giving up", pid, status)
+                }
+                None => format!("Child process with pid {} fatal exit, giving up", pid),
+            },
+        ),
+        Event::NotRestarted { pid, policy } => logger(
+            LogLevel::INFO,
+            format!(
+                "Child process with pid {} not restarted, restart policy is {}",
+                pid, policy
+            ),
+        ),
+        Event::ChildError { error } => {
+            logger(LogLevel::ERROR, format!("Command error -> {}", error))
+        }
+        Event::KillSent { pid } => logger(LogLevel::INFO, format!("Kill done for pid {}", pid)),
+        Event::KillFailed { pid } => logger(LogLevel::ERROR, format!("Cannot kill pid {}", pid)),
+        Event::GaveUp { errors } => logger(
+            LogLevel::ERROR,
+            format!("Too many errors in a row ({}), giving up", errors),
+        ),
+    }
+}
@@ -0,0 +1,397 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Kind of network change
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Interface is down or removed
+    LinkDown,
+    /// Default route added, changed or removed
+    DefaultRoute,
+    /// Address removed from interface
+    AddressRemoved,
+    /// Socket overrun, changes were lost
+    Overrun,
+}
+impl fmt::Display for ChangeKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChangeKind::LinkDown => write!(f, "link_down"),
+            ChangeKind::DefaultRoute => write!(f, "default_route"),
+            ChangeKind::AddressRemoved => write!(f, "address_removed"),
+            ChangeKind::Overrun => write!(f, "overrun"),
+        }
+    }
+}
+
+/// A network change reported by the kernel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkChange {
+    pub kind: ChangeKind,
+    /// Interface name, if known
+    pub interface: Option<String>,
+}
+impl fmt::Display for NetworkChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.interface {
+            Some(interface) => write!(f, "{} {}", self.kind, interface),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+/// Listens to netlink link, route and address changes in a thread, debounced. Linux only.
+///
+/// The thread and its socket end when the listener is dropped.
+#[derive(Debug)]
+pub struct NetlinkListener {
+    receiver: Receiver<NetworkChange>,
+    debounce: Duration,
+    pending: Vec<NetworkChange>,
+    last_change: Option<Instant>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl NetlinkListener {
+    /// Starts listening, changes are ready after `debounce` without more changes
+    pub fn start(debounce: Duration) -> io::Result<Self> {
+        let (sender, receiver) = channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = listen(sender, stop.clone())?;
+        Ok(NetlinkListener {
+            receiver,
+            debounce,
+            pending: Vec::new(),
+            last_change: None,
+            stop,
+            thread: Some(thread),
+        })
+    }
+    /// Gets changes once they settle, without waiting
+    pub fn changes(&mut self) -> Vec<NetworkChange> {
+        for change in self.receiver.try_iter() {
+            self.last_change = Some(Instant::now());
+            if !self.pending.contains(&change) {
+                self.pending.push(change);
+            }
+        }
+        match self.last_change {
+            Some(last) if last.elapsed() >= self.debounce => {
+                self.last_change = None;
+                self.pending.drain(..).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Drop for NetlinkListener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Time between checks of the stop flag while waiting for messages
+const POLL_MS: i32 = 200;
+
+#[cfg(target_os = "linux")]
+fn listen(sender: Sender<NetworkChange>, stop: Arc<AtomicBool>) -> io::Result<JoinHandle<()>> {
+    use nix::errno::Errno;
+    use nix::poll::{poll, PollFd, PollFlags};
+    use nix::sys::socket::{bind, recv, socket, AddressFamily, MsgFlags, SockAddr, SockFlag};
+    use nix::sys::socket::{SockProtocol, SockType};
+    use nix::unistd::close;
+
+    // RTMGRP_LINK, RTMGRP_IPV4_IFADDR, RTMGRP_IPV4_ROUTE, RTMGRP_IPV6_IFADDR, RTMGRP_IPV6_ROUTE
+    let groups = 0x1 | 0x10 | 0x40 | 0x100 | 0x400;
+    let fd = socket(
+        AddressFamily::Netlink,
+        SockType::Raw,
+        SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+        SockProtocol::NetlinkRoute,
+    )?;
+    if let Err(err) = bind(fd, &SockAddr::new_netlink(0, groups)) {
+        close(fd).ok();
+        return Err(err.into());
+    }
+    Ok(thread::spawn(move || {
+        let mut buffer = vec![0; 16384];
+        'listen: while !stop.load(Ordering::SeqCst) {
+            let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+            match poll(&mut fds, POLL_MS) {
+                Ok(0) | Err(Errno::EINTR) => continue,
+                Ok(_) => (),
+                Err(_) => break,
+            }
+            loop {
+                match recv(fd, &mut buffer, MsgFlags::empty()) {
+                    Ok(n) => {
+                        for change in parse_messages(&buffer[..n]) {
+                            if sender.send(change).is_err() {
+                                break 'listen;
+                            }
+                        }
+                    }
+                    Err(Errno::EAGAIN) | Err(Errno::EINTR) => break,
+                    // lost messages, check anyway
+                    Err(Errno::ENOBUFS) => {
+                        let change = NetworkChange {
+                            kind: ChangeKind::Overrun,
+                            interface: None,
+                        };
+                        if sender.send(change).is_err() {
+                            break 'listen;
+                        }
+                    }
+                    Err(_) => break 'listen,
+                }
+            }
+        }
+        close(fd).ok();
+    }))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn listen(_sender: Sender<NetworkChange>, _stop: Arc<AtomicBool>) -> io::Result<JoinHandle<()>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "netlink is Linux only",
+    ))
+}
+
+// Message types
+const RTM_NEWLINK: u16 = 16;
+const RTM_DELLINK: u16 = 17;
+const RTM_DELADDR: u16 = 21;
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+// Sizes
+const NLMSG_HDRLEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+const RTMSG_LEN: usize = 12;
+// Route attribute, output interface
+const RTA_OIF: u16 = 4;
+// Interface flags
+const IFF_UP: u32 = 0x1;
+const IFF_RUNNING: u32 = 0x40;
+
+/// Parses netlink messages into interesting changes, in host byte order
+fn parse_messages(buffer: &[u8]) -> Vec<NetworkChange> {
+    let mut changes = Vec::new();
+    let mut offset = 0;
+    while offset + NLMSG_HDRLEN <= buffer.len() {
+        let len = read_u32(buffer, offset) as usize;
+        if len < NLMSG_HDRLEN || offset + len > buffer.len() {
+            break;
+        }
+        let kind = read_u16(buffer, offset + 4);
+        let body = &buffer[offset + NLMSG_HDRLEN..offset + len];
+        if let Some(change) = parse_message(kind, body) {
+            changes.push(change);
+        }
+        // aligned to 4 bytes
+        offset += (len + 3) & !3;
+    }
+    changes
+}
+
+fn parse_message(kind: u16, body: &[u8]) -> Option<NetworkChange> {
+    match kind {
+        // ifinfomsg: family, pad, type, index, flags, change
+        RTM_NEWLINK | RTM_DELLINK if body.len() >= IFINFOMSG_LEN => {
+            let index = read_u32(body, 4);
+            let flags = read_u32(body, 8);
+            let up = flags & IFF_UP != 0 && flags & IFF_RUNNING != 0;
+            if kind == RTM_DELLINK || !up {
+                Some(NetworkChange {
+                    kind: ChangeKind::LinkDown,
+                    interface: interface_name(index),
+                })
+            } else {
+                None
+            }
+        }
+        // ifaddrmsg: family, prefix length, flags, scope, index
+        RTM_DELADDR if body.len() >= 8 => Some(NetworkChange {
+            kind: ChangeKind::AddressRemoved,
+            interface: interface_name(read_u32(body, 4)),
+        }),
+        // rtmsg: family, dst length, ...; default route has no destination
+        RTM_NEWROUTE | RTM_DELROUTE if body.len() >= RTMSG_LEN && body[1] == 0 => {
+            let index = route_attribute(&body[RTMSG_LEN..], RTA_OIF)
+                .filter(|value| value.len() >= 4)
+                .map(|value| read_u32(value, 0));
+            Some(NetworkChange {
+                kind: ChangeKind::DefaultRoute,
+                interface: index.and_then(interface_name),
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Finds a route attribute value by type
+fn route_attribute(mut attributes: &[u8], kind: u16) -> Option<&[u8]> {
+    while attributes.len() >= 4 {
+        let len = read_u16(attributes, 0) as usize;
+        if len < 4 || len > attributes.len() {
+            return None;
+        }
+        if read_u16(attributes, 2) == kind {
+            return Some(&attributes[4..len]);
+        }
+        attributes = &attributes[((len + 3) & !3).min(attributes.len())..];
+    }
+    None
+}
+
+/// Gets interface name by index, from `/sys/class/net`
+fn interface_name(index: u32) -> Option<String> {
+    fs::read_dir("/sys/class/net")
+        .ok()?
+        .filter_map(Result::ok)
+        .find(|entry| {
+            fs::read_to_string(entry.path().join("ifindex"))
+                .map(|ifindex| ifindex.trim() == index.to_string())
+                .unwrap_or(false)
+        })
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+}
+
+fn read_u16(buffer: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buffer[offset], buffer[offset + 1]])
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buffer[offset..offset + 4]);
+    u32::from_ne_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(kind: u16, body: &[u8]) -> Vec<u8> {
+        let mut message = Vec::new();
+        message.extend_from_slice(&((NLMSG_HDRLEN + body.len()) as u32).to_ne_bytes());
+        message.extend_from_slice(&kind.to_ne_bytes());
+        message.extend_from_slice(&[0; 10]);
+        message.extend_from_slice(body);
+        message
+    }
+    fn link(index: u32, flags: u32) -> Vec<u8> {
+        let mut body = vec![0; 4];
+        body.extend_from_slice(&index.to_ne_bytes());
+        body.extend_from_slice(&flags.to_ne_bytes());
+        body.extend_from_slice(&[0; 4]);
+        body
+    }
+
+    #[test]
+    fn links() {
+        // loopback, usually 1
+        let lo = fs::read_to_string("/sys/class/net/lo/ifindex")
+            .ok()
+            .and_then(|index| index.trim().parse().ok())
+            .unwrap_or(1);
+        let mut buffer = message(RTM_NEWLINK, &link(lo, IFF_UP | IFF_RUNNING));
+        buffer.extend(message(RTM_NEWLINK, &link(lo, IFF_UP)));
+        buffer.extend(message(RTM_DELLINK, &link(lo, 0)));
+        let changes = parse_messages(&buffer);
+        assert_eq!(changes.len(), 2);
+        assert!(changes
+            .iter()
+            .all(|change| change.kind == ChangeKind::LinkDown));
+        #[cfg(target_os = "linux")]
+        assert_eq!(changes[0].interface, Some(String::from("lo")));
+    }
+    #[test]
+    fn routes() {
+        // default route through interface 1
+        let mut body = vec![2, 0, 0, 0, 254, 3, 0, 1, 0, 0, 0, 0];
+        body.extend_from_slice(&8u16.to_ne_bytes());
+        body.extend_from_slice(&RTA_OIF.to_ne_bytes());
+        body.extend_from_slice(&1u32.to_ne_bytes());
+        let changes = parse_messages(&message(RTM_DELROUTE, &body));
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].kind, ChangeKind::DefaultRoute);
+        // not a default route
+        body[1] = 24;
+        assert!(parse_messages(&message(RTM_NEWROUTE, &body)).is_empty());
+        // truncated
+        assert!(parse_messages(&message(RTM_NEWROUTE, &body)[..20]).is_empty());
+    }
+    #[test]
+    fn debounce() {
+        let (sender, receiver) = channel();
+        let mut listener = NetlinkListener {
+            receiver,
+            debounce: Duration::from_millis(50),
+            pending: Vec::new(),
+            last_change: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        };
+        let change = NetworkChange {
+            kind: ChangeKind::AddressRemoved,
+            interface: Some(String::from("eth0")),
+        };
+        sender.send(change.clone()).unwrap();
+        sender.send(change.clone()).unwrap();
+        assert!(listener.changes().is_empty());
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(listener.changes(), vec![change]);
+        assert!(listener.changes().is_empty());
+        assert_eq!(
+            NetworkChange {
+                kind: ChangeKind::LinkDown,
+                interface: Some(String::from("tun0"))
+            }
+            .to_string(),
+            "link_down tun0"
+        );
+        let overrun = NetworkChange {
+            kind: ChangeKind::Overrun,
+            interface: None,
+        };
+        assert_eq!(overrun.to_string(), "overrun");
+    }
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn drop_stops() {
+        let listener = NetlinkListener::start(Duration::from_millis(50)).unwrap();
+        let start = Instant::now();
+        drop(listener);
+        // thread joined, it checks the stop flag on every poll
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
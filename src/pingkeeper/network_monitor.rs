@@ -18,13 +18,30 @@
 
 use rand::{seq::SliceRandom, thread_rng};
 
-use std::net::SocketAddr;
-use std::process;
+use std::sync::Arc;
 use std::time::Duration;
 
+use super::command::Command;
+
 mod tools;
 use tools::*;
 
+mod diagnosis;
+pub use diagnosis::FailureLayer;
+
+pub(crate) mod interface;
+
+mod ping_stats;
+pub use ping_stats::PingStats;
+
+mod probe;
+
+mod quality;
+pub use probe::{
+    CommandProbe, HttpProbe, InterfaceProbe, PingProbe, Probe, ProbeOutcome, Source, TcpProbe,
+};
+pub use quality::{Quality, QualityThresholds};
+
 const DEFAULT_TIMEOUT: u64 = 2;
 
 /// Network monitor errors
@@ -38,95 +55,108 @@ pub enum NetworkError {
 /// Network monitor
 pub struct NetworkMonitor {
     hosts: Vec<String>,
-    addresses: Vec<SocketAddr>,
+    port: Option<u16>,
+    probe: Arc<dyn Probe>,
     ping_opt: Option<String>,
     timeout: Duration,
+    interfaces: Vec<String>,
+    interface_probe: Arc<dyn Probe>,
 }
 
 // Public
 impl NetworkMonitor {
-    /// Instantiates a new NetworkMonitor
+    /// Instantiates a new NetworkMonitor, checking with a TCP probe by default
     pub fn new(hosts: Vec<String>, port: Option<u16>) -> Self {
-        let addresses = hosts_to_addresses(&hosts, port);
         NetworkMonitor {
             hosts,
-            addresses,
+            port,
+            probe: Arc::new(TcpProbe::new(port)),
             ping_opt: None,
             timeout: Duration::from_secs(DEFAULT_TIMEOUT),
+            interfaces: vec![],
+            interface_probe: Arc::new(InterfaceProbe::new()),
         }
     }
-    /// Checks network status
-    pub fn check(&self, check_cmd: &Option<String>, use_ping: bool) -> Result<(), NetworkError> {
-        if let Some(cmd) = check_cmd {
-            self.check_custom_cmd(cmd)
-        } else if use_ping {
-            self.is_ping_pong()
+    /// Checks network status with the required interfaces and the current probe
+    pub fn check(&self) -> Result<(), NetworkError> {
+        let outcomes = self.probe_hosts()?;
+        if outcomes.iter().any(|outcome| outcome.success) {
+            Ok(())
         } else {
-            self.is_network_reachable()
+            Err(NetworkError::NetworkUnreachable)
         }
     }
+    /// Probes required interfaces, then hosts with the current probe until one succeeds
+    ///
+    /// Only failed interfaces are returned, hosts are not probed if any fails.
+    pub fn probe_hosts(&self) -> Result<Vec<ProbeOutcome>, NetworkError> {
+        let failures = self.probe_interfaces();
+        if !failures.is_empty() {
+            return Ok(failures);
+        }
+        self.probe_hosts_with(&self.probe)
+    }
+    /// Probes a group of hosts with a probe, or the current one, until `quorum` of them succeed
+    pub fn probe_group(
+        &self,
+        hosts: &[String],
+        probe: Option<&Arc<dyn Probe>>,
+        quorum: usize,
+    ) -> Result<Vec<ProbeOutcome>, NetworkError> {
+        self.probe_quorum(hosts, probe.unwrap_or(&self.probe), quorum)
+    }
+    /// Probes a target several times with the current probe, one after another
+    pub fn sample(&self, target: &str, samples: usize) -> Quality {
+        let outcomes: Vec<ProbeOutcome> = (0..samples)
+            .map(|_| self.probe.probe(target, self.timeout))
+            .collect();
+        Quality::from_outcomes(&outcomes)
+    }
     /// Checks if ping answers with a pong
     pub fn is_ping_pong(&self) -> Result<(), NetworkError> {
-        if self.hosts.is_empty() {
-            return Err(NetworkError::NoHostsToCheck);
-        }
-        let mut hosts = self.hosts.to_vec();
-        let mut rng = thread_rng();
-        hosts.shuffle(&mut rng);
-        let mut ping_opt = String::new();
-        if let Some(opt) = &self.ping_opt {
-            ping_opt = String::from(opt)
-        };
-        if can_ping_some(hosts, ping_opt) {
-            Ok(())
-        } else {
-            Err(NetworkError::NetworkUnreachable)
-        }
+        let ping_opt = self.ping_opt.clone().unwrap_or_default();
+        self.check_with(&(Arc::new(PingProbe::new(ping_opt)) as Arc<dyn Probe>))
     }
     /// Checks if network is reachable
     pub fn is_network_reachable(&self) -> Result<(), NetworkError> {
-        if self.addresses.is_empty() {
-            return Err(NetworkError::NoHostsToCheck);
-        }
-        let mut rng = thread_rng();
-        let mut addresses = self.addresses.clone();
-        addresses.shuffle(&mut rng);
-        if can_connect_some(addresses, self.timeout) {
-            Ok(())
-        } else {
-            Err(NetworkError::NetworkUnreachable)
-        }
+        self.check_with(&(Arc::new(TcpProbe::new(self.port)) as Arc<dyn Probe>))
     }
     /// Checks custom command exit status
-    pub fn check_custom_cmd(&self, cmd: &str) -> Result<(), NetworkError> {
-        let success = process::Command::new("/bin/sh")
-            .arg("-c")
-            .arg(cmd)
-            .env("__PK_HOSTS", self.hosts.join(" "))
-            .output()
-            .expect("No shell?")
-            .status
-            .success();
-        if success {
-            Ok(())
+    pub fn check_custom_cmd<C: Into<Command>>(&self, cmd: C) -> Result<(), NetworkError> {
+        self.check_with(&(Arc::new(CommandProbe::new(cmd)) as Arc<dyn Probe>))
+    }
+
+    /// Finds the layer of a network failure: link, gateway, dns or internet
+    ///
+    /// Required interfaces failing are a link failure.
+    pub fn diagnose(&self) -> FailureLayer {
+        if !self.probe_interfaces().is_empty() {
+            FailureLayer::Link
         } else {
-            Err(NetworkError::NetworkUnreachable)
+            diagnosis::diagnose(self.timeout)
         }
     }
-
-    // /// Sets port, for is_network_reachable
-    // pub fn set_port(&mut self, port: u16) {
-    //   self.port = Some(port);
-    //   self.addresses = hosts_to_addresses(&self.hosts, self.port);
-    // }
+    /// Sets the probe used by `check`
+    pub fn set_probe(&mut self, probe: Arc<dyn Probe>) {
+        self.probe = probe;
+    }
+    /// Gets the probe used by `check`
+    pub fn get_probe(&self) -> &Arc<dyn Probe> {
+        &self.probe
+    }
+    /// Sets interfaces that must pass an interface probe before probing hosts
+    pub fn set_interfaces(&mut self, interfaces: Vec<String>, probe: InterfaceProbe) {
+        self.interfaces = interfaces;
+        self.interface_probe = Arc::new(probe);
+    }
     /// Sets ping options, for is_ping_pong
     pub fn set_ping_opt(&mut self, ping_opt: String) {
         self.ping_opt = Some(ping_opt);
     }
-    /// Sets timeout for direct connection
-    pub fn set_timeout(&mut self, secs: u64) -> Result<(), NetworkError> {
-        if secs > 0 {
-            self.timeout = Duration::from_secs(secs);
+    /// Sets timeout for probes
+    pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), NetworkError> {
+        if timeout > Duration::from_secs(0) {
+            self.timeout = timeout;
             Ok(())
         } else {
             Err(NetworkError::InvalidTimeout)
@@ -134,6 +164,47 @@ impl NetworkMonitor {
     }
 }
 
+// Private
+impl NetworkMonitor {
+    /// Checks network status with a probe
+    fn check_with(&self, probe: &Arc<dyn Probe>) -> Result<(), NetworkError> {
+        let outcomes = self.probe_hosts_with(probe)?;
+        if outcomes.iter().any(|outcome| outcome.success) {
+            Ok(())
+        } else {
+            Err(NetworkError::NetworkUnreachable)
+        }
+    }
+    /// Probes required interfaces, until one fails
+    pub fn probe_interfaces(&self) -> Vec<ProbeOutcome> {
+        self.interfaces
+            .iter()
+            .map(|name| self.interface_probe.probe(name, self.timeout))
+            .find(|outcome| !outcome.success)
+            .into_iter()
+            .collect()
+    }
+    /// Probes shuffled targets with a probe, until one succeeds
+    fn probe_hosts_with(&self, probe: &Arc<dyn Probe>) -> Result<Vec<ProbeOutcome>, NetworkError> {
+        self.probe_quorum(&self.hosts, probe, 1)
+    }
+    /// Probes shuffled targets of some hosts with a probe, until `quorum` of them succeed
+    fn probe_quorum(
+        &self,
+        hosts: &[String],
+        probe: &Arc<dyn Probe>,
+        quorum: usize,
+    ) -> Result<Vec<ProbeOutcome>, NetworkError> {
+        let mut targets = probe.targets(&expand_hosts(hosts));
+        if targets.is_empty() {
+            return Err(NetworkError::NoHostsToCheck);
+        }
+        let mut rng = thread_rng();
+        targets.shuffle(&mut rng);
+        Ok(probe_some(probe, targets, self.timeout, quorum))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -147,8 +218,8 @@ mod tests {
     fn set_timeout() {
         let hosts = vec![String::from("8.8.8.8")];
         let mut network = NetworkMonitor::new(hosts, None);
-        assert!(network.set_timeout(0).is_err());
-        assert!(network.set_timeout(2).is_ok());
+        assert!(network.set_timeout(Duration::from_secs(0)).is_err());
+        assert!(network.set_timeout(Duration::from_secs(2)).is_ok());
     }
     // Custom command
     #[test]
@@ -193,27 +264,84 @@ mod tests {
         assert!(err.is_err());
         assert_eq!(err.unwrap_err(), NetworkError::NetworkUnreachable);
     }
-    // Check priorities
+    // Probes
     #[test]
-    fn check_priority_custom() {
+    fn check_probe_custom() {
         let hosts = vec![String::from("127.0.0.2")];
-        let custom = NetworkMonitor::new(hosts, None);
+        let mut custom = NetworkMonitor::new(hosts, None);
+        custom.set_probe(Arc::new(CommandProbe::new("echo")));
         // ping and tcp would fail
-        assert!(custom.check(&Some(String::from("echo")), true).is_ok());
+        assert!(custom.check().is_ok());
     }
     #[test]
-    fn check_priority_ping() {
+    fn check_probe_ping() {
         let hosts = vec![String::from("127.0.0.1")];
         let mut custom = NetworkMonitor::new(hosts, Some(0));
-        custom.set_ping_opt(String::from("-c1"));
+        custom.set_probe(Arc::new(PingProbe::new("-c1")));
         // tcp would fail
-        assert!(custom.check(&None, true).is_ok());
+        assert!(custom.check().is_ok());
     }
     #[test]
-    fn check_priority_tcp() {
+    fn check_probe_tcp() {
         let hosts = vec![String::from("1.0.0.1")];
         let custom = NetworkMonitor::new(hosts, Some(53));
-        // tcp would fail
-        assert!(custom.check(&None, false).is_ok());
+        // tcp is the default probe
+        assert!(custom.check().is_ok());
+    }
+    /// Answers only for one target
+    struct OnlyProbe(&'static str);
+    impl Probe for OnlyProbe {
+        fn name(&self) -> &str {
+            "only"
+        }
+        fn probe(&self, target: &str, _timeout: Duration) -> ProbeOutcome {
+            if target == self.0 {
+                ProbeOutcome::success(target, None)
+            } else {
+                ProbeOutcome::failure(target, "not the one")
+            }
+        }
+    }
+    #[test]
+    fn check_probe_user_defined() {
+        let hosts = vec![String::from("a"), String::from("b"), String::from("c")];
+        let mut network = NetworkMonitor::new(hosts, None);
+        network.set_probe(Arc::new(OnlyProbe("b")));
+        assert!(network.check().is_ok());
+        let outcomes = network.probe_hosts().unwrap();
+        assert_eq!(outcomes.iter().filter(|o| o.success).count(), 1);
+        network.set_probe(Arc::new(OnlyProbe("d")));
+        assert_eq!(network.check(), Err(NetworkError::NetworkUnreachable));
+        assert_eq!(network.probe_hosts().unwrap().len(), 3);
+    }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn check_interfaces() {
+        let hosts = vec![String::from("127.0.0.2")];
+        let mut network = NetworkMonitor::new(hosts, None);
+        network.set_probe(Arc::new(CommandProbe::new("echo")));
+        network.set_interfaces(vec![String::from("lo")], InterfaceProbe::new());
+        assert!(network.check().is_ok());
+        let interfaces = vec![String::from("lo"), String::from("__pingkeeper__")];
+        network.set_interfaces(interfaces, InterfaceProbe::new());
+        assert_eq!(network.check(), Err(NetworkError::NetworkUnreachable));
+        let outcomes = network.probe_hosts().unwrap();
+        assert_eq!(outcomes.len(), 1);
+        assert_eq!(outcomes[0].target, "__pingkeeper__");
+    }
+    #[test]
+    fn probe_group_quorum() {
+        let hosts: Vec<String> = vec!["a", "b", "c"].into_iter().map(String::from).collect();
+        let network = NetworkMonitor::new(vec![], None);
+        let probe: Arc<dyn Probe> = Arc::new(OnlyProbe("b"));
+        let outcomes = network.probe_group(&hosts, Some(&probe), 1).unwrap();
+        assert_eq!(outcomes.iter().filter(|o| o.success).count(), 1);
+        // quorum can not be reached, all probed
+        let outcomes = network.probe_group(&hosts, Some(&probe), 2).unwrap();
+        assert_eq!(outcomes.len(), 3);
+        assert_eq!(
+            network.probe_group(&[], Some(&probe), 1),
+            Err(NetworkError::NoHostsToCheck)
+        );
     }
 }
@@ -0,0 +1,296 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::process::{self, Command};
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::interface::{self, DefaultRoute};
+use super::probe::Source;
+use super::tools::{connect_timeout, host_to_address, nameservers, ping, scoped};
+
+/// Layer of a network failure, from the closest to the farthest
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FailureLayer {
+    /// No default route, or its interface is down or a required interface failed
+    Link,
+    /// Gateways of default routes do not answer
+    Gateway,
+    /// Nameservers do not answer
+    Dns,
+    /// Everything answers but hosts
+    Internet,
+}
+impl fmt::Display for FailureLayer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FailureLayer::Link => write!(f, "link"),
+            FailureLayer::Gateway => write!(f, "gateway"),
+            FailureLayer::Dns => write!(f, "dns"),
+            FailureLayer::Internet => write!(f, "internet"),
+        }
+    }
+}
+impl FromStr for FailureLayer {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "link" => Ok(FailureLayer::Link),
+            "gateway" => Ok(FailureLayer::Gateway),
+            "dns" => Ok(FailureLayer::Dns),
+            "internet" => Ok(FailureLayer::Internet),
+            _ => Err(format!("unknown layer: `{}`", s)),
+        }
+    }
+}
+
+/// Walks the layers up to the first one failing, after required interfaces passed
+pub fn diagnose(timeout: Duration) -> FailureLayer {
+    let routes = interface::default_routes();
+    let is_link_up = routes.iter().any(|route| {
+        interface::is_up(&route.interface) && interface::has_carrier(&route.interface)
+    });
+    if !is_link_up {
+        return FailureLayer::Link;
+    }
+    let gateways = gateways(&routes);
+    if !gateways.is_empty()
+        && !gateways
+            .iter()
+            .any(|gateway| is_gateway_up(gateway, timeout))
+    {
+        return FailureLayer::Gateway;
+    }
+    let resolvers: Vec<SocketAddr> = nameservers()
+        .iter()
+        .filter_map(|nameserver| host_to_address(nameserver, Some(53)))
+        .collect();
+    if !resolvers.is_empty() && !resolvers.iter().any(|resolver| resolves(resolver, timeout)) {
+        return FailureLayer::Dns;
+    }
+    FailureLayer::Internet
+}
+
+/// Gateways of default routes, link-local IPv6 ones scoped to their interface like `fe80::1%eth0`
+fn gateways(routes: &[DefaultRoute]) -> Vec<String> {
+    routes
+        .iter()
+        .filter_map(|route| match route.gateway? {
+            IpAddr::V6(ip) => Some(scoped(ip, &route.interface)),
+            ip => Some(ip.to_string()),
+        })
+        .collect()
+}
+
+/// Does a gateway answer to ping, or resolve as a neighbour? Many routers drop TCP, so it is the last resort
+fn is_gateway_up(gateway: &str, timeout: Duration) -> bool {
+    let seconds = timeout.as_secs().max(1);
+    let ping_opt = format!("-c 1 -W {}", seconds);
+    if ping(&ping_opt, gateway, &Source::default()).0 {
+        return true;
+    }
+    // the ping resolved its link address even if the answer was dropped
+    let is_neighbour = match gateway.parse::<IpAddr>() {
+        Ok(ip @ IpAddr::V4(_)) => fs::read_to_string("/proc/net/arp")
+            .map(|arp| is_arp_complete(&arp, ip))
+            .unwrap_or(false),
+        _ => is_ipv6_neighbour(gateway),
+    };
+    is_neighbour || host_to_address(gateway, Some(53)).is_some_and(|addr| answers(&addr, timeout))
+}
+
+/// Has the IPv6 neighbour table a link address for the gateway? Asks `ip -6 neigh`
+fn is_ipv6_neighbour(gateway: &str) -> bool {
+    let mut cmd = Command::new("ip");
+    cmd.env("LC_ALL", "C").args(["-6", "neigh", "show"]);
+    match gateway.split_once('%') {
+        Some((ip, interface)) => cmd.arg(ip).arg("dev").arg(interface),
+        None => cmd.arg(gateway),
+    };
+    cmd.output()
+        .map(|output| is_neigh_complete(&String::from_utf8_lossy(&output.stdout)))
+        .unwrap_or(false)
+}
+
+/// Has `ip neigh show` output a resolved entry? Failed and incomplete ones have no link address
+fn is_neigh_complete(output: &str) -> bool {
+    output.lines().any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.contains(&"lladdr")
+            && !matches!(fields.last(), Some(&"FAILED") | Some(&"INCOMPLETE"))
+    })
+}
+
+/// Has the ARP table a complete entry for the address?
+fn is_arp_complete(arp: &str, addr: IpAddr) -> bool {
+    let addr = addr.to_string();
+    arp.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // IP address, HW type, Flags, HW address, Mask, Device
+        fields.len() >= 4
+            && fields[0] == addr
+            && u32::from_str_radix(fields[2].trim_start_matches("0x"), 16)
+                .map(|flags| flags & 0x2 != 0)
+                .unwrap_or(false)
+    })
+}
+
+/// Does a host answer to TCP? A refused connection is an answer too
+fn answers(addr: &SocketAddr, timeout: Duration) -> bool {
+    match connect_timeout(addr, timeout, &Source::default()) {
+        Ok(_) => true,
+        Err(err) => err.kind() == io::ErrorKind::ConnectionRefused,
+    }
+}
+
+/// Does a nameserver resolve? Asks for root nameservers
+fn resolves(addr: &SocketAddr, timeout: Duration) -> bool {
+    let local = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = match UdpSocket::bind(local) {
+        Ok(socket) => socket,
+        Err(_) => return false,
+    };
+    if socket.set_read_timeout(Some(timeout)).is_err() {
+        return false;
+    }
+    let id = process::id() as u16;
+    if socket.send_to(&dns_query(id), addr).is_err() {
+        return false;
+    }
+    let mut response = [0; 512];
+    match socket.recv_from(&mut response) {
+        Ok((len, from)) if from.ip() == addr.ip() => is_dns_answer(&response[..len], id),
+        _ => false,
+    }
+}
+
+/// DNS query for NS records of the root, with recursion desired
+fn dns_query(id: u16) -> Vec<u8> {
+    let mut query = id.to_be_bytes().to_vec();
+    // flags RD, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    // root, NS, IN
+    query.extend_from_slice(&[0x00, 0x00, 0x02, 0x00, 0x01]);
+    query
+}
+
+/// Is it a response to the query, without SERVFAIL or REFUSED?
+fn is_dns_answer(response: &[u8], id: u16) -> bool {
+    response.len() >= 12
+        && response[..2] == id.to_be_bytes()
+        && response[2] & 0x80 != 0
+        && ![2, 5].contains(&(response[3] & 0x0f))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layer_names() {
+        for layer in ["link", "gateway", "dns", "internet"].iter() {
+            assert_eq!(FailureLayer::from_str(layer).unwrap().to_string(), *layer);
+        }
+        assert!(FailureLayer::from_str("physical").is_err());
+    }
+    #[test]
+    fn dns_answer() {
+        let query = dns_query(0x1234);
+        assert_eq!(query.len(), 17);
+        assert!(!is_dns_answer(&query, 0x1234));
+        let mut response = query.clone();
+        response[2] |= 0x80;
+        assert!(is_dns_answer(&response, 0x1234));
+        assert!(!is_dns_answer(&response, 0x4321));
+        // SERVFAIL
+        response[3] |= 0x02;
+        assert!(!is_dns_answer(&response, 0x1234));
+    }
+    #[test]
+    fn refused_answers() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(answers(&addr, Duration::from_secs(1)));
+        drop(listener);
+        assert!(answers(&addr, Duration::from_secs(1)));
+    }
+    #[test]
+    fn link_local_gateway() {
+        let routes = vec![
+            DefaultRoute {
+                interface: String::from("eth0"),
+                gateway: Some("fe80::1".parse().unwrap()),
+            },
+            DefaultRoute {
+                interface: String::from("eth1"),
+                gateway: Some("2001:db8::1".parse().unwrap()),
+            },
+            DefaultRoute {
+                interface: String::from("eth2"),
+                gateway: Some("192.168.1.1".parse().unwrap()),
+            },
+            DefaultRoute {
+                interface: String::from("tun0"),
+                gateway: None,
+            },
+        ];
+        assert_eq!(
+            gateways(&routes),
+            vec!["fe80::1%eth0", "2001:db8::1", "192.168.1.1"]
+        );
+        // scoped for the tcp fallback
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            host_to_address("fe80::1%lo", Some(53)).map(|addr| addr.to_string()),
+            Some(String::from("[fe80::1%1]:53"))
+        );
+    }
+    #[test]
+    fn neigh_complete() {
+        assert!(is_neigh_complete(
+            "fe80::1 dev eth0 lladdr aa:bb:cc:dd:ee:ff router REACHABLE\n"
+        ));
+        assert!(is_neigh_complete(
+            "fe80::1 lladdr aa:bb:cc:dd:ee:ff router STALE\n"
+        ));
+        assert!(!is_neigh_complete("fe80::1 dev eth0 FAILED\n"));
+        assert!(!is_neigh_complete("fe80::1 dev eth0 INCOMPLETE\n"));
+        assert!(!is_neigh_complete(""));
+    }
+    #[test]
+    fn arp_complete() {
+        let arp = "IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         aa:bb:cc:dd:ee:ff     *        eth0
+192.168.1.7      0x1         0x0         00:00:00:00:00:00     *        eth0
+";
+        assert!(is_arp_complete(arp, "192.168.1.1".parse().unwrap()));
+        // incomplete
+        assert!(!is_arp_complete(arp, "192.168.1.7".parse().unwrap()));
+        assert!(!is_arp_complete(arp, "192.168.1.2".parse().unwrap()));
+        assert!(!is_arp_complete(arp, "fe80::1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,184 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use nix::ifaddrs::getifaddrs;
+use nix::sys::socket::AddressFamily;
+
+use std::fs;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
+
+/// IFF_UP
+const IFF_UP: u32 = 0x1;
+/// RTF_UP
+const RTF_UP: u32 = 0x1;
+/// RTF_REJECT, unreachable routes
+const RTF_REJECT: u32 = 0x200;
+
+/// Reads an attribute of a network interface from sysfs
+fn attribute(name: &str, attribute: &str) -> Option<String> {
+    let path = PathBuf::from("/sys/class/net").join(name).join(attribute);
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+}
+
+/// Does network interface exist?
+pub fn exists(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && PathBuf::from("/sys/class/net").join(name).exists()
+}
+
+/// Is network interface administratively up and not down?
+pub fn is_up(name: &str) -> bool {
+    let flags = attribute(name, "flags")
+        .and_then(|flags| u32::from_str_radix(flags.trim_start_matches("0x"), 16).ok());
+    let operstate = attribute(name, "operstate").unwrap_or_default();
+    // tunnels are `unknown`
+    flags.is_some_and(|flags| flags & IFF_UP != 0)
+        && operstate != "down"
+        && operstate != "lowerlayerdown"
+}
+
+/// Has network interface a carrier? Unknown carrier counts as one
+pub fn has_carrier(name: &str) -> bool {
+    attribute(name, "carrier").as_deref() != Some("0")
+}
+
+/// Has network interface an address of this family?
+pub fn has_address(name: &str, family: AddressFamily) -> bool {
+    getifaddrs().is_ok_and(|mut addresses| {
+        addresses.any(|ifaddr| {
+            ifaddr.interface_name == name
+                && ifaddr
+                    .address
+                    .is_some_and(|address| address.family() == family)
+        })
+    })
+}
+
+/// A default route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DefaultRoute {
+    /// Egress interface
+    pub interface: String,
+    /// Gateway, none for direct routes
+    pub gateway: Option<IpAddr>,
+}
+
+/// Default routes, IPv4 first and then IPv6
+pub fn default_routes() -> Vec<DefaultRoute> {
+    let mut routes = parse_route(&fs::read_to_string("/proc/net/route").unwrap_or_default());
+    routes.extend(parse_ipv6_route(
+        &fs::read_to_string("/proc/net/ipv6_route").unwrap_or_default(),
+    ));
+    routes
+}
+
+/// Interfaces of the default routes, IPv4 and IPv6
+pub fn default_route_interfaces() -> Vec<String> {
+    default_routes()
+        .into_iter()
+        .map(|route| route.interface)
+        .collect()
+}
+
+/// Default routes in `/proc/net/route`, addresses in host byte order
+fn parse_route(content: &str) -> Vec<DefaultRoute> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let flags = u32::from_str_radix(fields.get(3)?, 16).ok()?;
+            if fields[1] == "00000000" && fields.get(7)? == &"00000000" && flags & RTF_UP != 0 {
+                let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+                Some(DefaultRoute {
+                    interface: fields[0].to_string(),
+                    gateway: Some(gateway)
+                        .filter(|gateway| *gateway != 0)
+                        .map(|gateway| IpAddr::from(gateway.to_le_bytes())),
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Default routes in `/proc/net/ipv6_route`
+fn parse_ipv6_route(content: &str) -> Vec<DefaultRoute> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let flags = u32::from_str_radix(fields.get(8)?, 16).ok()?;
+            let is_default = fields[0].chars().all(|c| c == '0') && fields[1] == "00";
+            if is_default && flags & RTF_UP != 0 && flags & RTF_REJECT == 0 {
+                let gateway = u128::from_str_radix(fields[4], 16).ok()?;
+                Some(DefaultRoute {
+                    interface: fields.get(9)?.to_string(),
+                    gateway: Some(gateway)
+                        .filter(|gateway| *gateway != 0)
+                        .map(|gateway| IpAddr::from(Ipv6Addr::from(gateway))),
+                })
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route() {
+        let content =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+            eth0\t00000000\t010200C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n\
+            eth0\t000200C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n";
+        let route = DefaultRoute {
+            interface: String::from("eth0"),
+            gateway: Some("192.0.2.1".parse().unwrap()),
+        };
+        assert_eq!(parse_route(content), vec![route]);
+        assert!(parse_route("").is_empty());
+    }
+    #[test]
+    fn ipv6_route() {
+        let content = "fd000000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0\n\
+            00000000000000000000000000000000 00 00000000000000000000000000000000 00 fd000000000000000000000000000001 00000400 00000001 00000000 00000003     tun0\n\
+            00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo\n";
+        let route = DefaultRoute {
+            interface: String::from("tun0"),
+            gateway: Some("fd00::1".parse().unwrap()),
+        };
+        assert_eq!(parse_ipv6_route(content), vec![route]);
+    }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn loopback() {
+        assert!(exists("lo"));
+        assert!(is_up("lo"));
+        assert!(has_address("lo", AddressFamily::Inet));
+        assert!(!exists("__pingkeeper__"));
+        assert!(!exists("../lo"));
+        assert!(!is_up("__pingkeeper__"));
+    }
+}
@@ -0,0 +1,160 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::time::Duration;
+
+/// Statistics of a `ping` run, from its summary lines
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PingStats {
+    pub transmitted: u32,
+    pub received: u32,
+    pub min: Option<Duration>,
+    pub avg: Option<Duration>,
+    pub max: Option<Duration>,
+    /// Standard deviation, `mdev` or `stddev`
+    pub mdev: Option<Duration>,
+}
+
+impl PingStats {
+    /// Lost packets, in percent
+    pub fn loss(&self) -> u32 {
+        (self.transmitted.saturating_sub(self.received) * 100)
+            .checked_div(self.transmitted)
+            .unwrap_or(0)
+    }
+    /// Parses summary of iputils, busybox or BSD `ping`, none if there is no summary
+    pub fn parse(output: &str) -> Option<Self> {
+        let summary = output
+            .lines()
+            .find(|line| line.contains("packets transmitted"))?;
+        let count = |word: &str| {
+            summary
+                .split(',')
+                .find(|part| part.contains(word))
+                .and_then(|part| part.split_whitespace().next())
+                .and_then(|count| count.parse().ok())
+        };
+        let rtt: Vec<Duration> = output
+            .lines()
+            .find(|line| line.starts_with("rtt ") || line.starts_with("round-trip "))
+            .and_then(|line| line.split('=').nth(1))
+            .map(|values| {
+                values
+                    .trim()
+                    .trim_end_matches("ms")
+                    .split('/')
+                    .filter_map(|value| value.trim().parse::<f64>().ok())
+                    .map(|millis| Duration::from_secs_f64(millis / 1000.0))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Some(PingStats {
+            transmitted: count("transmitted")?,
+            received: count("received")?,
+            min: rtt.first().copied(),
+            avg: rtt.get(1).copied(),
+            max: rtt.get(2).copied(),
+            mdev: rtt.get(3).copied(),
+        })
+    }
+}
+
+impl fmt::Display for PingStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} received, {}% loss",
+            self.received,
+            self.transmitted,
+            self.loss()
+        )?;
+        if let (Some(min), Some(avg), Some(max)) = (self.min, self.avg, self.max) {
+            let millis = |rtt: Duration| format!("{:.3}", rtt.as_secs_f64() * 1000.0);
+            write!(
+                f,
+                ", rtt min/avg/max/mdev {}/{}/{}/{} ms",
+                millis(min),
+                millis(avg),
+                millis(max),
+                self.mdev.map(millis).unwrap_or_else(|| String::from("-"))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(millis: f64) -> Option<Duration> {
+        Some(Duration::from_secs_f64(millis / 1000.0))
+    }
+
+    #[test]
+    fn iputils() {
+        let output = "PING 1.1.1.1 (1.1.1.1) 56(84) bytes of data.\n\
+            64 bytes from 1.1.1.1: icmp_seq=1 ttl=57 time=14.2 ms\n\
+            \n\
+            --- 1.1.1.1 ping statistics ---\n\
+            4 packets transmitted, 3 received, 25% packet loss, time 3004ms\n\
+            rtt min/avg/max/mdev = 14.119/14.210/14.301/0.074 ms\n";
+        let stats = PingStats::parse(output).unwrap();
+        assert_eq!(
+            (stats.transmitted, stats.received, stats.loss()),
+            (4, 3, 25)
+        );
+        assert_eq!(stats.avg, millis(14.210));
+        assert_eq!(stats.mdev, millis(0.074));
+        assert_eq!(
+            stats.to_string(),
+            "3/4 received, 25% loss, rtt min/avg/max/mdev 14.119/14.210/14.301/0.074 ms"
+        );
+        let output =
+            "2 packets transmitted, 0 received, +2 errors, 100% packet loss, time 1001ms\n";
+        let stats = PingStats::parse(output).unwrap();
+        assert_eq!((stats.received, stats.loss(), stats.avg), (0, 100, None));
+        assert_eq!(stats.to_string(), "0/2 received, 100% loss");
+    }
+    #[test]
+    fn busybox() {
+        let output = "--- 1.1.1.1 ping statistics ---\n\
+            1 packets transmitted, 1 packets received, 0% packet loss\n\
+            round-trip min/avg/max = 0.058/0.058/0.058 ms\n";
+        let stats = PingStats::parse(output).unwrap();
+        assert_eq!((stats.transmitted, stats.received), (1, 1));
+        assert_eq!(stats.min, millis(0.058));
+        assert_eq!(stats.mdev, None);
+    }
+    #[test]
+    fn bsd() {
+        let output = "--- 1.1.1.1 ping statistics ---\n\
+            5 packets transmitted, 5 packets received, 0.0% packet loss\n\
+            round-trip min/avg/max/stddev = 14.1/15.2/16.3/0.9 ms\n";
+        let stats = PingStats::parse(output).unwrap();
+        assert_eq!((stats.transmitted, stats.received, stats.loss()), (5, 5, 0));
+        assert_eq!(stats.max, millis(16.3));
+        assert_eq!(stats.mdev, millis(0.9));
+    }
+    #[test]
+    fn no_summary() {
+        assert_eq!(PingStats::parse(""), None);
+        assert_eq!(PingStats::parse("ping: unknown host nope"), None);
+    }
+}
@@ -0,0 +1,553 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use nix::sys::socket::AddressFamily;
+use nix::unistd::{setpgid, Pid};
+
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::os::unix::process::CommandExt;
+use std::process;
+use std::time::{Duration, Instant};
+
+use super::super::command::Command;
+use super::super::credentials::Credentials;
+use super::interface;
+use super::ping_stats::PingStats;
+use super::tools::{connect_timeout, host_to_address, ping, wait_timeout};
+
+/// Outcome of probing a single target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProbeOutcome {
+    /// Probed target
+    pub target: String,
+    /// Did the target answer?
+    pub success: bool,
+    /// Time until the answer, if measured
+    pub latency: Option<Duration>,
+    /// Error detail on failure
+    pub error: Option<String>,
+    /// Statistics of the `ping` summary, if parsed
+    pub ping: Option<PingStats>,
+}
+
+impl ProbeOutcome {
+    /// Creates a successful outcome
+    pub fn success(target: &str, latency: Option<Duration>) -> Self {
+        ProbeOutcome {
+            target: String::from(target),
+            success: true,
+            latency,
+            error: None,
+            ping: None,
+        }
+    }
+    /// Creates a failed outcome
+    pub fn failure<S: Into<String>>(target: &str, error: S) -> Self {
+        ProbeOutcome {
+            target: String::from(target),
+            success: false,
+            latency: None,
+            error: Some(error.into()),
+            ping: None,
+        }
+    }
+    /// Attaches statistics of a `ping` run
+    pub fn ping_stats(mut self, stats: Option<PingStats>) -> Self {
+        self.ping = stats;
+        self
+    }
+}
+
+/// A network check
+///
+/// Network is reachable if probing one of the targets succeeds.
+pub trait Probe: Send + Sync {
+    /// Name of the probe, for logging
+    fn name(&self) -> &str;
+    /// Targets to probe for a list of hosts, one per host by default
+    fn targets(&self, hosts: &[String]) -> Vec<String> {
+        hosts.to_vec()
+    }
+    /// Probes a single target
+    fn probe(&self, target: &str, timeout: Duration) -> ProbeOutcome;
+}
+
+/// Where probes send traffic from, instead of the route picked by the kernel
+///
+/// Binding to an interface, like `tun0`, tests traffic through it only.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Source {
+    pub(super) address: Option<IpAddr>,
+    pub(super) interface: Option<String>,
+}
+
+impl Source {
+    /// Sets the source address
+    pub fn address(mut self, address: Option<IpAddr>) -> Self {
+        self.address = address;
+        self
+    }
+    /// Sets the network interface, `SO_BINDTODEVICE`. Linux only
+    pub fn interface<S: Into<String>>(mut self, interface: Option<S>) -> Self {
+        self.interface = interface.map(Into::into);
+        self
+    }
+    /// Neither address nor interface
+    pub fn is_any(&self) -> bool {
+        self.address.is_none() && self.interface.is_none()
+    }
+    /// Interface, or else address, for `ping -I`
+    pub(super) fn ping_interface(&self) -> Option<String> {
+        self.interface
+            .clone()
+            .or_else(|| self.address.map(|address| address.to_string()))
+    }
+}
+
+// ---------------------- TCP ----------------------
+
+/// Direct TCP connection
+#[derive(Debug, Clone)]
+pub struct TcpProbe {
+    port: Option<u16>,
+    source: Source,
+}
+
+impl TcpProbe {
+    /// Creates a TCP probe, with a default port for hosts without port
+    pub fn new(port: Option<u16>) -> Self {
+        TcpProbe {
+            port,
+            source: Source::default(),
+        }
+    }
+    /// Connects from a source address or interface
+    pub fn source(mut self, source: Source) -> Self {
+        self.source = source;
+        self
+    }
+}
+
+impl Probe for TcpProbe {
+    fn name(&self) -> &str {
+        "tcp"
+    }
+    /// Only valid addresses, with port
+    fn targets(&self, hosts: &[String]) -> Vec<String> {
+        hosts
+            .iter()
+            .filter_map(|host| host_to_address(host, self.port))
+            .map(|addr| addr.to_string())
+            .collect()
+    }
+    fn probe(&self, target: &str, timeout: Duration) -> ProbeOutcome {
+        let addr = match host_to_address(target, self.port) {
+            Some(addr) => addr,
+            None => return ProbeOutcome::failure(target, "invalid address"),
+        };
+        let start = Instant::now();
+        match connect_timeout(&addr, timeout, &self.source) {
+            Ok(_) => ProbeOutcome::success(target, Some(start.elapsed())),
+            Err(err) => ProbeOutcome::failure(target, err.to_string()),
+        }
+    }
+}
+
+// ---------------------- HTTP ----------------------
+
+/// HTTP `GET` request, targets are `http://` urls
+///
+/// Succeeds on any 2xx or 3xx status.
+#[derive(Debug, Clone, Default)]
+pub struct HttpProbe {
+    source: Source,
+}
+
+impl HttpProbe {
+    /// Creates an HTTP probe
+    pub fn new() -> Self {
+        HttpProbe::default()
+    }
+    /// Connects from a source address or interface
+    pub fn source(mut self, source: Source) -> Self {
+        self.source = source;
+        self
+    }
+}
+
+impl Probe for HttpProbe {
+    fn name(&self) -> &str {
+        "http"
+    }
+    fn probe(&self, target: &str, timeout: Duration) -> ProbeOutcome {
+        let (address, host, path) = match split_url(target) {
+            Some(url) => url,
+            None => return ProbeOutcome::failure(target, "invalid url"),
+        };
+        let start = Instant::now();
+        match http_status(&address, &host, &path, timeout, &self.source) {
+            Ok(status) if (200..400).contains(&status) => {
+                ProbeOutcome::success(target, Some(start.elapsed()))
+            }
+            Ok(status) => ProbeOutcome::failure(target, format!("http status {}", status)),
+            Err(err) => ProbeOutcome::failure(target, err.to_string()),
+        }
+    }
+}
+
+/// Splits an `http://` url into address with port, host and path
+fn split_url(url: &str) -> Option<(SocketAddr, String, String)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        return None;
+    }
+    // port after IPv6 brackets, if any
+    let has_port = authority
+        .rfind(':')
+        .is_some_and(|i| match authority.rfind(']') {
+            Some(j) => i > j,
+            None => true,
+        });
+    let address = if has_port {
+        authority.to_socket_addrs()
+    } else {
+        format!("{}:80", authority).to_socket_addrs()
+    };
+    let address = address.ok()?.next()?;
+    Some((address, String::from(authority), String::from(path)))
+}
+
+/// Requests a path and gets the response status
+fn http_status(
+    address: &SocketAddr,
+    host: &str,
+    path: &str,
+    timeout: Duration,
+    source: &Source,
+) -> std::io::Result<u16> {
+    let mut stream = connect_timeout(address, timeout, source)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream.write_all(request.as_bytes())?;
+    // status line is enough
+    let mut response = [0; 64];
+    let mut len = 0;
+    while len < response.len() {
+        match stream.read(&mut response[len..])? {
+            0 => break,
+            n => len += n,
+        }
+        if response[..len].contains(&b'\n') {
+            break;
+        }
+    }
+    String::from_utf8_lossy(&response[..len])
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "not http"))
+}
+
+// ---------------------- Ping ----------------------
+
+/// System's `ping` command, timeout is ignored
+#[derive(Debug, Clone)]
+pub struct PingProbe {
+    ping_opt: String,
+    source: Source,
+}
+
+impl PingProbe {
+    /// Creates a ping probe with options for `ping` command
+    pub fn new<S: Into<String>>(ping_opt: S) -> Self {
+        PingProbe {
+            ping_opt: ping_opt.into(),
+            source: Source::default(),
+        }
+    }
+    /// Pings from a source interface or address, `ping -I`
+    pub fn source(mut self, source: Source) -> Self {
+        self.source = source;
+        self
+    }
+}
+
+impl Probe for PingProbe {
+    fn name(&self) -> &str {
+        "ping"
+    }
+    fn probe(&self, target: &str, _timeout: Duration) -> ProbeOutcome {
+        let start = Instant::now();
+        let (success, stats) = ping(&self.ping_opt, target, &self.source);
+        let outcome = if success {
+            let latency = stats.as_ref().and_then(|stats| stats.avg);
+            ProbeOutcome::success(target, latency.or_else(|| Some(start.elapsed())))
+        } else {
+            match &stats {
+                Some(stats) => ProbeOutcome::failure(target, format!("no pong, {}", stats)),
+                None => ProbeOutcome::failure(target, "no pong"),
+            }
+        };
+        outcome.ping_stats(stats)
+    }
+}
+
+// ---------------------- Interface ----------------------
+
+/// Network interface state, targets are interface names, timeout is ignored
+///
+/// Succeeds if the interface exists, is up and has a carrier.
+#[derive(Debug, Clone, Default)]
+pub struct InterfaceProbe {
+    ipv4: bool,
+    ipv6: bool,
+    default_route: bool,
+}
+
+impl InterfaceProbe {
+    /// Creates an interface probe
+    pub fn new() -> Self {
+        InterfaceProbe::default()
+    }
+    /// Requires an IPv4 address
+    pub fn ipv4(mut self, ipv4: bool) -> Self {
+        self.ipv4 = ipv4;
+        self
+    }
+    /// Requires an IPv6 address
+    pub fn ipv6(mut self, ipv6: bool) -> Self {
+        self.ipv6 = ipv6;
+        self
+    }
+    /// Requires the interface to be the egress of a default route
+    pub fn default_route(mut self, default_route: bool) -> Self {
+        self.default_route = default_route;
+        self
+    }
+}
+
+impl Probe for InterfaceProbe {
+    fn name(&self) -> &str {
+        "interface"
+    }
+    fn probe(&self, target: &str, _timeout: Duration) -> ProbeOutcome {
+        let start = Instant::now();
+        let error = if !interface::exists(target) {
+            Some("no such interface")
+        } else if !interface::is_up(target) {
+            Some("interface down")
+        } else if !interface::has_carrier(target) {
+            Some("no carrier")
+        } else if self.ipv4 && !interface::has_address(target, AddressFamily::Inet) {
+            Some("no ipv4 address")
+        } else if self.ipv6 && !interface::has_address(target, AddressFamily::Inet6) {
+            Some("no ipv6 address")
+        } else if self.default_route
+            && !interface::default_route_interfaces()
+                .iter()
+                .any(|name| name == target)
+        {
+            Some("not default route")
+        } else {
+            None
+        };
+        match error {
+            Some(error) => ProbeOutcome::failure(target, error),
+            None => ProbeOutcome::success(target, Some(start.elapsed())),
+        }
+    }
+}
+
+// ---------------------- Command ----------------------
+
+/// Custom command exit status
+///
+/// Runs once for all hosts, available to the command as `__PK_HOSTS`. After its own timeout, if any,
+/// the command and its children are killed and the probe fails.
+#[derive(Debug, Clone)]
+pub struct CommandProbe {
+    command: Command,
+    credentials: Credentials,
+    timeout: Option<Duration>,
+}
+
+impl CommandProbe {
+    /// Creates a command probe
+    pub fn new<C: Into<Command>>(command: C) -> Self {
+        CommandProbe {
+            command: command.into(),
+            credentials: Credentials::default(),
+            timeout: None,
+        }
+    }
+    /// Kills the command after a timeout, instead of waiting for it; the timeout of each probe is ignored
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Runs the command with other user and groups
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = credentials;
+        self
+    }
+}
+
+impl Probe for CommandProbe {
+    fn name(&self) -> &str {
+        "cmd"
+    }
+    /// Single target with all hosts
+    fn targets(&self, hosts: &[String]) -> Vec<String> {
+        vec![hosts.join(" ")]
+    }
+    fn probe(&self, target: &str, _timeout: Duration) -> ProbeOutcome {
+        let start = Instant::now();
+        let mut cmd = self.command.to_process();
+        cmd.env("__PK_HOSTS", target)
+            .stdin(process::Stdio::null())
+            .stdout(process::Stdio::null())
+            .stderr(process::Stdio::null());
+        self.credentials.apply(&mut cmd);
+        // own process group, to kill its children on timeout
+        // SAFETY: setpgid is async-signal-safe
+        unsafe {
+            cmd.pre_exec(|| setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(io::Error::from));
+        }
+        let status = cmd.spawn().and_then(|mut child| match self.timeout {
+            Some(timeout) => wait_timeout(child, timeout),
+            None => child.wait().map(Some),
+        });
+        match status {
+            Ok(Some(status)) if status.success() => {
+                ProbeOutcome::success(target, Some(start.elapsed()))
+            }
+            Ok(Some(status)) => ProbeOutcome::failure(target, status.to_string()),
+            Ok(None) => ProbeOutcome::failure(target, "timed out"),
+            Err(err) => ProbeOutcome::failure(target, err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tcp_targets() {
+        let probe = TcpProbe::new(Some(53));
+        let hosts = vec![String::from("1.1.1.1"), String::from("example.com")];
+        assert_eq!(probe.targets(&hosts), vec![String::from("1.1.1.1:53")]);
+    }
+    #[test]
+    fn tcp_failure() {
+        let probe = TcpProbe::new(Some(53));
+        let outcome = probe.probe("255.255.255.255", Duration::from_secs(1));
+        assert!(!outcome.success);
+        assert!(outcome.error.is_some());
+    }
+    #[test]
+    fn http_url() {
+        let (address, host, path) = split_url("http://127.0.0.1:8080/health?full=1").unwrap();
+        assert_eq!(address, "127.0.0.1:8080".parse().unwrap());
+        assert_eq!(host, "127.0.0.1:8080");
+        assert_eq!(path, "/health?full=1");
+        let (address, _, path) = split_url("http://[::1]").unwrap();
+        assert_eq!(address, "[::1]:80".parse().unwrap());
+        assert_eq!(path, "/");
+        assert!(split_url("https://127.0.0.1/").is_none());
+        assert!(split_url("http:///").is_none());
+    }
+    #[test]
+    fn http_status() {
+        use std::net::TcpListener;
+        use std::thread;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for status in ["200 OK", "503 Service Unavailable"].iter() {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0; 128];
+                let _ = stream.read(&mut request);
+                write!(stream, "HTTP/1.0 {}\r\n\r\n", status).unwrap();
+            }
+        });
+        let probe = HttpProbe::new();
+        assert!(probe.probe(&url, Duration::from_secs(1)).success);
+        let outcome = probe.probe(&url, Duration::from_secs(1));
+        assert!(!outcome.success);
+        assert_eq!(outcome.error, Some(String::from("http status 503")));
+    }
+    #[test]
+    fn command_targets() {
+        let probe = CommandProbe::new("echo");
+        let hosts = vec![String::from("1.1.1.1"), String::from("8.8.8.8")];
+        assert_eq!(probe.targets(&hosts), vec![String::from("1.1.1.1 8.8.8.8")]);
+    }
+    #[test]
+    fn command_hosts_env() {
+        let probe = CommandProbe::new("test \"$__PK_HOSTS\" = \"1.1.1.1 8.8.8.8\"");
+        let outcome = probe.probe("1.1.1.1 8.8.8.8", Duration::from_secs(1));
+        assert!(outcome.success);
+        assert!(outcome.latency.is_some());
+        let outcome = probe.probe("1.1.1.1", Duration::from_secs(1));
+        assert!(!outcome.success);
+    }
+    #[test]
+    fn command_timeout() {
+        let probe = CommandProbe::new("sleep 5; true").timeout(Some(Duration::from_millis(200)));
+        let start = Instant::now();
+        let outcome = probe.probe("", Duration::from_secs(10));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(outcome.error, Some(String::from("timed out")));
+        // waits without its own timeout
+        let probe = CommandProbe::new("sleep 0.3; true");
+        assert!(probe.probe("", Duration::from_millis(100)).success);
+    }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn interface() {
+        let probe = InterfaceProbe::new();
+        assert!(probe.probe("lo", Duration::from_secs(1)).success);
+        let outcome = probe.probe("__pingkeeper__", Duration::from_secs(1));
+        assert_eq!(outcome.error, Some(String::from("no such interface")));
+        let outcome = probe
+            .default_route(true)
+            .probe("lo", Duration::from_secs(1));
+        assert_eq!(outcome.error, Some(String::from("not default route")));
+    }
+    #[test]
+    fn source() {
+        let source = Source::default();
+        assert!(source.is_any());
+        assert_eq!(source.ping_interface(), None);
+        let source = source.address(Some("10.8.0.2".parse().unwrap()));
+        assert_eq!(source.ping_interface(), Some(String::from("10.8.0.2")));
+        let source = source.interface(Some("tun0"));
+        assert!(!source.is_any());
+        assert_eq!(source.ping_interface(), Some(String::from("tun0")));
+    }
+}
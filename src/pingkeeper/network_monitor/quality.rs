@@ -0,0 +1,160 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::time::Duration;
+
+use super::probe::ProbeOutcome;
+
+/// Latency and loss of several probes to a target
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quality {
+    pub samples: usize,
+    pub lost: usize,
+    /// Median latency of answered probes
+    pub p50: Option<Duration>,
+    /// 95th percentile latency of answered probes
+    pub p95: Option<Duration>,
+}
+
+impl Quality {
+    /// Measures quality of probe outcomes, failed ones are lost
+    pub fn from_outcomes(outcomes: &[ProbeOutcome]) -> Self {
+        let mut latencies: Vec<Duration> = outcomes
+            .iter()
+            .filter(|outcome| outcome.success)
+            .filter_map(|outcome| outcome.latency)
+            .collect();
+        latencies.sort();
+        Quality {
+            samples: outcomes
+                .iter()
+                .map(|outcome| {
+                    outcome
+                        .ping
+                        .as_ref()
+                        .map_or(1, |stats| stats.transmitted as usize)
+                })
+                .sum(),
+            lost: outcomes
+                .iter()
+                .map(|outcome| match &outcome.ping {
+                    Some(stats) => stats.transmitted.saturating_sub(stats.received) as usize,
+                    None => usize::from(!outcome.success),
+                })
+                .sum(),
+            p50: percentile(&latencies, 50),
+            p95: percentile(&latencies, 95),
+        }
+    }
+    /// Lost probes, in percent
+    pub fn loss(&self) -> u32 {
+        (self.lost * 100).checked_div(self.samples).unwrap_or(0) as u32
+    }
+}
+
+/// Nearest-rank percentile of sorted values
+// `div_ceil` needs Rust 1.73
+#[allow(clippy::manual_div_ceil)]
+fn percentile(sorted: &[Duration], p: usize) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len() + 99) / 100;
+    Some(sorted[rank.max(1) - 1])
+}
+
+/// Maximum latency and loss of a network that is not degraded
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QualityThresholds {
+    pub p50: Option<Duration>,
+    pub p95: Option<Duration>,
+    /// Percent
+    pub loss: Option<u32>,
+}
+
+impl QualityThresholds {
+    /// Are there any thresholds?
+    pub fn is_empty(&self) -> bool {
+        self.p50.is_none() && self.p95.is_none() && self.loss.is_none()
+    }
+    /// Is quality over any threshold? Everything lost is degraded too
+    pub fn is_degraded(&self, quality: &Quality) -> bool {
+        let over = |latency: Option<Duration>, max: Option<Duration>| match (latency, max) {
+            (Some(latency), Some(max)) => latency > max,
+            (None, Some(_)) => true,
+            _ => false,
+        };
+        over(quality.p50, self.p50)
+            || over(quality.p95, self.p95)
+            || self.loss.is_some_and(|loss| quality.loss() > loss)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::ping_stats::PingStats;
+    use super::*;
+
+    fn outcomes(latencies: &[Option<u64>]) -> Vec<ProbeOutcome> {
+        latencies
+            .iter()
+            .map(|latency| match latency {
+                Some(millis) => ProbeOutcome::success("a", Some(Duration::from_millis(*millis))),
+                None => ProbeOutcome::failure("a", "lost"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quality() {
+        let quality =
+            Quality::from_outcomes(&outcomes(&[Some(40), Some(10), None, Some(30), Some(20)]));
+        assert_eq!(quality.samples, 5);
+        assert_eq!(quality.loss(), 20);
+        assert_eq!(quality.p50, Some(Duration::from_millis(20)));
+        assert_eq!(quality.p95, Some(Duration::from_millis(40)));
+        let quality = Quality::from_outcomes(&[]);
+        assert_eq!((quality.loss(), quality.p50), (0, None));
+    }
+    #[test]
+    fn quality_ping_stats() {
+        let stats = PingStats::parse("4 packets transmitted, 3 received, 25% packet loss").unwrap();
+        let mut outcomes = outcomes(&[Some(10), None]);
+        outcomes[0] = outcomes[0].clone().ping_stats(Some(stats));
+        let quality = Quality::from_outcomes(&outcomes);
+        assert_eq!((quality.samples, quality.lost), (5, 2));
+        assert_eq!(quality.loss(), 40);
+    }
+    #[test]
+    fn thresholds() {
+        let quality = Quality::from_outcomes(&outcomes(&[Some(100), Some(4000), None]));
+        let mut thresholds = QualityThresholds::default();
+        assert!(thresholds.is_empty());
+        assert!(!thresholds.is_degraded(&quality));
+        thresholds.loss = Some(50);
+        assert!(!thresholds.is_degraded(&quality));
+        thresholds.p95 = Some(Duration::from_secs(1));
+        assert!(thresholds.is_degraded(&quality));
+        let lost = Quality::from_outcomes(&outcomes(&[None]));
+        let thresholds = QualityThresholds {
+            p50: Some(Duration::from_secs(1)),
+            ..QualityThresholds::default()
+        };
+        assert!(thresholds.is_degraded(&lost));
+    }
+}
@@ -16,68 +16,229 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use nix::errno::Errno;
+use nix::net::if_::if_nametoindex;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::socket::{
+    bind, connect, socket, AddressFamily, InetAddr, SockAddr, SockFlag, SockType,
+};
+use nix::unistd::Pid;
 use pipeliner::Pipeline;
 use std::process;
 
-use std::net::{IpAddr, SocketAddr, TcpStream};
-use std::time::Duration;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
-/// Pings a host and returns if it is reachable
-fn ping(ping_opt: &str, host: &str) -> bool {
-    process::Command::new("/bin/sh")
-        .arg("-c")
-        .arg(&format!("ping {} {}", ping_opt, host))
+use super::interface;
+use super::ping_stats::PingStats;
+use super::probe::{Probe, ProbeOutcome, Source};
+
+/// Pings a host and returns if it is reachable, with the statistics of its summary if any
+pub fn ping(ping_opt: &str, host: &str, source: &Source) -> (bool, Option<PingStats>) {
+    let mut cmd = process::Command::new("ping");
+    // summary in English, whatever the user's locale
+    cmd.env("LC_ALL", "C").args(ping_opt.split_whitespace());
+    if let Some(interface) = source.ping_interface() {
+        cmd.arg("-I").arg(interface);
+    }
+    cmd.arg(host)
         .output()
-        .expect("No shell?")
-        .status
-        .success()
+        .map(|output| {
+            let stats = PingStats::parse(&String::from_utf8_lossy(&output.stdout));
+            (output.status.success(), stats)
+        })
+        .unwrap_or((false, None))
 }
 
-/// Checks if a ping replies from one host at least
-pub fn can_ping_some(hosts: Vec<String>, ping_opt: String) -> bool {
-    if ping(&ping_opt, &hosts[0]) {
-        return true;
-    }
-    let n = hosts.len();
-    for result in hosts.with_threads(n).map(move |s| ping(&ping_opt, &s)) {
-        if result {
-            return true;
+/// Waits for a child process, its process group is killed after the timeout
+pub fn wait_timeout(
+    mut child: process::Child,
+    timeout: Duration,
+) -> io::Result<Option<process::ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
         }
+        if Instant::now() >= deadline {
+            killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL).ok();
+            child.kill().ok();
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(10));
     }
-    false
 }
 
-/// Checks if a connection can be established to one address at least
-pub fn can_connect_some(addresses: Vec<SocketAddr>, timeout: Duration) -> bool {
-    if TcpStream::connect_timeout(&addresses[0], timeout).is_ok() {
-        return true;
+/// Probes targets until `quorum` of them succeed, first one alone and then the rest in parallel
+pub fn probe_some(
+    probe: &Arc<dyn Probe>,
+    targets: Vec<String>,
+    timeout: Duration,
+    quorum: usize,
+) -> Vec<ProbeOutcome> {
+    let first = probe.probe(&targets[0], timeout);
+    let mut successes = usize::from(first.success);
+    if successes >= quorum || targets.len() == 1 {
+        return vec![first];
     }
-    let n = addresses.len();
-    for result in addresses
+    let mut outcomes = vec![first];
+    let rest: Vec<String> = targets.into_iter().skip(1).collect();
+    let n = rest.len();
+    let probe = probe.clone();
+    for outcome in rest
         .with_threads(n)
-        .map(move |addr| TcpStream::connect_timeout(&addr, timeout).is_ok())
+        .map(move |target| probe.probe(&target, timeout))
     {
-        if result {
-            return true;
+        successes += usize::from(outcome.success);
+        outcomes.push(outcome);
+        if successes >= quorum {
+            break;
+        }
+    }
+    outcomes
+}
+
+/// Opens a TCP connection from a source address or interface, with timeout
+pub fn connect_timeout(
+    addr: &SocketAddr,
+    timeout: Duration,
+    source: &Source,
+) -> io::Result<TcpStream> {
+    if source.is_any() {
+        return TcpStream::connect_timeout(addr, timeout);
+    }
+    let family = if addr.is_ipv4() {
+        AddressFamily::Inet
+    } else {
+        AddressFamily::Inet6
+    };
+    let fd = socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)?;
+    // closes the socket on errors
+    // SAFETY: fd is a new socket owned by nothing else, its ownership is transferred to the stream
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    if let Some(interface) = &source.interface {
+        bind_to_device(&stream, interface)?;
+    }
+    if let Some(address) = source.address {
+        let address = SocketAddr::new(address, 0);
+        bind(fd, &SockAddr::new_inet(InetAddr::from_std(&address)))?;
+    }
+    stream.set_nonblocking(true)?;
+    match connect(fd, &SockAddr::new_inet(InetAddr::from_std(addr))) {
+        Ok(()) => {}
+        Err(Errno::EINPROGRESS) => {
+            let mut fds = [PollFd::new(fd, PollFlags::POLLOUT)];
+            let millis = timeout.as_millis().min(i32::MAX as u128) as i32;
+            if poll(&mut fds, millis)? == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection timed out",
+                ));
+            }
+            if let Some(err) = stream.take_error()? {
+                return Err(err);
+            }
         }
+        Err(err) => return Err(err.into()),
     }
-    false
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+/// Binds a socket to a network interface
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn bind_to_device<S: AsRawFd>(socket: &S, interface: &str) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt::BindToDevice};
+    setsockopt(socket.as_raw_fd(), BindToDevice, &interface.into())?;
+    Ok(())
 }
 
-/// Gets hosts as network addresses
-pub fn hosts_to_addresses(hosts: &[String], port: Option<u16>) -> Vec<SocketAddr> {
-    hosts
-        .iter()
-        .map(|host| host_to_address(host, port))
-        .filter(|addr| addr.is_some())
-        .map(|addr| addr.unwrap())
+/// Binds a socket to a network interface, unsupported
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+fn bind_to_device<S: AsRawFd>(_socket: &S, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "binding to an interface is not supported",
+    ))
+}
+
+/// Expands host tokens, resolved on every call
+///
+/// `@gateway` and `@gateway6` are the gateways of default routes, `@dns` the nameservers.
+pub fn expand_hosts(hosts: &[String]) -> Vec<String> {
+    if !hosts.iter().any(|host| host.starts_with('@')) {
+        return hosts.to_vec();
+    }
+    let mut expanded: Vec<String> = vec![];
+    for host in hosts {
+        let resolved = match host.as_str() {
+            "@gateway" | "@gateway6" => {
+                let ipv6 = host == "@gateway6";
+                interface::default_routes()
+                    .into_iter()
+                    .filter_map(|route| match route.gateway {
+                        Some(IpAddr::V6(ip)) if ipv6 => Some(scoped(ip, &route.interface)),
+                        Some(IpAddr::V4(ip)) if !ipv6 => Some(ip.to_string()),
+                        _ => None,
+                    })
+                    .collect()
+            }
+            "@dns" => nameservers(),
+            _ => vec![host.clone()],
+        };
+        for host in resolved {
+            if !expanded.contains(&host) {
+                expanded.push(host);
+            }
+        }
+    }
+    expanded
+}
+
+/// IPv6 address with the interface as scope if it is link-local, like `fe80::1%eth0`
+pub fn scoped(ip: Ipv6Addr, interface: &str) -> String {
+    if ip.segments()[0] & 0xffc0 == 0xfe80 {
+        format!("{}%{}", ip, interface)
+    } else {
+        ip.to_string()
+    }
+}
+
+/// Nameservers of the system
+pub fn nameservers() -> Vec<String> {
+    parse_nameservers(&fs::read_to_string("/etc/resolv.conf").unwrap_or_default())
+}
+
+/// Nameservers in `/etc/resolv.conf`
+fn parse_nameservers(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some("nameserver"), Some(address)) => Some(address.to_string()),
+                _ => None,
+            }
+        })
         .collect()
 }
 
 /// Gets host as network address
-fn host_to_address(host: &str, port: Option<u16>) -> Option<SocketAddr> {
+pub fn host_to_address(host: &str, port: Option<u16>) -> Option<SocketAddr> {
     if let Ok(addr) = host.parse::<SocketAddr>() {
         Some(addr)
+    } else if let (Some(port), Some((ip, scope))) = (port, host.split_once('%')) {
+        // IPv6 with scope, like `fe80::1%eth0`
+        let ip = ip.parse::<Ipv6Addr>().ok()?;
+        let scope = scope.parse().ok().or_else(|| if_nametoindex(scope).ok())?;
+        Some(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope)))
     } else {
         // IPv6 or IPv4
         match (port, host.parse::<IpAddr>()) {
@@ -93,8 +254,24 @@ mod tests {
 
     #[test]
     fn ping_function() {
-        assert!(ping("-c1", "127.0.0.1"));
-        assert!(!ping("-c1", "256.0.0.0"));
+        let (success, stats) = ping("-c1", "127.0.0.1", &Source::default());
+        assert!(success);
+        assert_eq!(stats.map(|stats| stats.received), Some(1));
+        assert!(!ping("-c1", "256.0.0.0", &Source::default()).0);
+    }
+    #[test]
+    fn connect_from_source() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let timeout = Duration::from_secs(1);
+        let source = Source::default().address(Some("127.0.0.2".parse().unwrap()));
+        let stream = connect_timeout(&addr, timeout, &source).unwrap();
+        let (_, peer) = listener.accept().unwrap();
+        assert_eq!(peer, stream.local_addr().unwrap());
+        assert_eq!(peer.ip().to_string(), "127.0.0.2");
+        // IPv6 source for an IPv4 address
+        let source = Source::default().address(Some("::1".parse().unwrap()));
+        assert!(connect_timeout(&addr, timeout, &source).is_err());
     }
 
     #[test]
@@ -105,6 +282,28 @@ mod tests {
             assert!(address.is_some());
         }
     }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn scoped_host_to_address() {
+        let address = host_to_address("fe80::1%lo", Some(53)).unwrap();
+        assert_eq!(address.to_string(), "[fe80::1%1]:53");
+        assert_eq!(host_to_address("fe80::1%1", Some(53)), Some(address));
+        assert_eq!(host_to_address("fe80::1%__pingkeeper__", Some(53)), None);
+    }
+    #[test]
+    fn expand_host_tokens() {
+        let hosts = vec![String::from("1.1.1.1"), String::from("1.1.1.1")];
+        assert_eq!(expand_hosts(&hosts), hosts);
+        let hosts = vec![String::from("1.1.1.1"), String::from("@unknown")];
+        assert_eq!(expand_hosts(&hosts), hosts);
+        assert_eq!(scoped("fe80::1".parse().unwrap(), "eth0"), "fe80::1%eth0");
+        assert_eq!(scoped("fd00::1".parse().unwrap(), "eth0"), "fd00::1");
+    }
+    #[test]
+    fn nameservers() {
+        let content = "# generated\nnameserver 192.0.2.53\nsearch lan\nnameserver fd00::53\n";
+        assert_eq!(parse_nameservers(content), vec!["192.0.2.53", "fd00::53"]);
+    }
     #[test]
     fn host_without_port_to_address() {
         let port = Some(8080);
@@ -16,6 +16,16 @@
     along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use regex::Regex;
+
+use super::escalation::EscalationStep;
+use super::expression::CheckExpr;
+use super::groups::GroupSpec;
+use super::network_monitor::FailureLayer;
+use super::policy::RestartPolicy;
+use super::rules::OutputRule;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 // Pingkeeper
@@ -27,15 +37,23 @@ use structopt::StructOpt;
 #[derive(StructOpt, Debug)]
 #[structopt(name = "Pingkeeper")]
 pub struct Opt {
-    /// Command to run.
-    #[structopt(name = "COMMAND")]
-    pub command: String,
+    /// Command to run, through `/bin/sh -c`.
+    #[structopt(name = "COMMAND", required_unless = "ARGV")]
+    pub command: Option<String>,
+    /// Program and arguments to run directly, without a shell.
+    ///
+    /// Signals reach the program itself. Example: `pingkeeper -- openvpn --config x.ovpn`
+    #[structopt(name = "ARGV", last = true, conflicts_with = "COMMAND")]
+    pub argv: Vec<String>,
     /// Space separated list of addresses or hosts (ping).
     ///
     /// For direct connection: List of IPv4 and IPv6, with or without port.
     ///
     /// For ping: List of hosts.
     ///
+    /// Tokens resolved on every check: `@gateway` and `@gateway6` for the gateways of default routes, `@dns` for
+    /// nameservers in `/etc/resolv.conf`.
+    ///
     /// Order does not matter, list will be shuffled.
     #[structopt(short = "H", long, default_value = "8.8.8.8 8.8.6.6 1.1.1.1 1.0.0.1")]
     pub hosts: String,
@@ -44,7 +62,7 @@ pub struct Opt {
     /// Port to connect if host does not have a port specified.
     #[structopt(short, long, default_value = "53")]
     pub port: u16,
-    /// Timeout in seconds, ignored if `--use-ping`.
+    /// Timeout in seconds of checks, ignored if `--use-ping`; also of liveness commands and for an adopted <COMMAND> to exit.
     #[structopt(short, long, default_value = "2")]
     pub timeout: u32,
 
@@ -54,29 +72,156 @@ pub struct Opt {
     #[structopt(short = "P", long)]
     pub use_ping: bool,
     /// Options for `ping` command, requires `--use-ping`.
-    #[structopt(long, name = "opts", default_value = "-c1")]
+    #[structopt(long, name = "ping-opt", value_name = "opts", default_value = "-c1")]
     pub ping_opt: String,
 
     /// Use a custom command to check.
     ///
     /// Check network or something else. This will trigger the execution / kill flow as if it was a network check.
-    /// Example: `--check-cmd "cat canary.txt"`
+    /// Example: `--check-cmd "cat canary.txt"`. It runs until it exits, or until `--check-timeout`.
     #[structopt(long)]
     pub check_cmd: Option<String>,
+    /// Seconds before killing `--check-cmd` and `cmd()` checks of `--check-expr`, failed then. No timeout by default.
+    #[structopt(long, name = "check-timeout", value_name = "check-seconds")]
+    pub check_timeout: Option<u64>,
+    /// Check with a boolean expression of checks, instead of `--hosts`, `--use-ping` and `--check-cmd`.
+    ///
+    /// Checks: `tcp(HOSTS)`, `ping(HOSTS)`, `http(URLS)`, `interface(NAMES)` pass if one of them answers, `--hosts`
+    /// or `--interface` without arguments; `cmd(COMMAND)` passes on exit status 0. Combined with `and`, `or`, `not` and parentheses,
+    /// evaluated from left to right until the result is known, after `--interface`.
+    /// Example: `--check-expr "tcp(1.1.1.1 8.8.8.8) and http(http://example.com/) or cmd(test -f canary)"`
+    #[structopt(long, name = "check-expr", value_name = "expression")]
+    pub check_expr: Option<CheckExpr>,
+    /// Network interface that must exist, be up and have a carrier, checked before hosts.
+    ///
+    /// Network is unreachable without probing hosts if it fails, it can be used multiple times.
+    /// Example: `--interface tun0`
+    #[structopt(long, name = "interface", value_name = "if", number_of_values = 1)]
+    pub interface: Vec<String>,
+    /// Interfaces must have an IPv4 address, requires `--interface`.
+    #[structopt(long, requires = "interface")]
+    pub interface_ipv4: bool,
+    /// Interfaces must have an IPv6 address, requires `--interface`.
+    #[structopt(long, requires = "interface")]
+    pub interface_ipv6: bool,
+    /// Interfaces must be the egress of a default route, requires `--interface`.
+    #[structopt(long, requires = "interface")]
+    pub interface_default_route: bool,
+    /// Source address of tcp and ping checks.
+    #[structopt(long, name = "source-address", value_name = "ip")]
+    pub source_address: Option<IpAddr>,
+    /// Network interface of tcp and ping checks, ignoring other routes. Linux only.
+    ///
+    /// Checks traffic through this interface only, like `--source-interface tun0`. `ping` gets it with `-I`.
+    #[structopt(long, name = "source-interface", value_name = "device")]
+    pub source_interface: Option<String>,
+    /// Named group of hosts with its own probe, quorum and action, repeatable.
+    ///
+    /// Format: `name=NAME,hosts=HOSTS[,probe=tcp|ping|http][,quorum=N][,action=ACTION]`, hosts space separated.
+    /// Probe defaults to the one of the network check, quorum to 1, up to the number of hosts. Actions, run when the group goes down: `log`
+    /// (default), `hook:COMMAND` (it gets `__PK_GROUP`) or `command`, network is unreachable for <COMMAND> too.
+    /// Example: `--host-group "name=lan,hosts=@gateway,probe=ping"`
+    #[structopt(long, name = "host-group", value_name = "GROUP", number_of_values = 1)]
+    pub host_group: Vec<GroupSpec>,
+    /// Network is degraded when median latency of answers exceeds these milliseconds.
+    ///
+    /// Quality is measured with `--samples` probes to the first host that answered, not with `--check-expr`.
+    #[structopt(long, name = "max-p50", value_name = "p50-millis")]
+    pub max_p50: Option<u64>,
+    /// Network is degraded when 95th percentile latency of answers exceeds these milliseconds.
+    #[structopt(long, name = "max-p95", value_name = "p95-millis")]
+    pub max_p95: Option<u64>,
+    /// Network is degraded when more than this percent of probes are lost.
+    #[structopt(long, name = "max-loss", value_name = "loss-percent")]
+    pub max_loss: Option<u32>,
+    /// Probes to measure quality of network, with `--max-p50`, `--max-p95` or `--max-loss`.
+    #[structopt(long, name = "samples", default_value = "5")]
+    pub samples: usize,
+    /// Command to run when network becomes degraded.
+    ///
+    /// It gets `__PK_P50_MS`, `__PK_P95_MS` and `__PK_LOSS_PERCENT`.
+    #[structopt(long, name = "degraded-cmd", value_name = "degraded-command")]
+    pub degraded_cmd: Option<String>,
+    /// Degraded network is unreachable for <COMMAND>.
+    #[structopt(long)]
+    pub degraded_is_down: bool,
+    /// Diagnose failed checks: link, gateway, dns or internet.
+    ///
+    /// <COMMAND> and hooks get the layer of the latest failure as `__PK_FAILURE_LAYER`.
+    #[structopt(long)]
+    pub diagnose: bool,
+    /// Run a command instead of <COMMAND> when a check fails at this layer, repeatable, requires `--diagnose`.
+    ///
+    /// Example: `--on-layer "link:ip link set tun0 up"`
+    #[structopt(long, name = "on-layer", value_name = "LAYER:COMMAND", number_of_values = 1, requires = "diagnose", parse(try_from_str = parse_layer_command))]
+    pub on_layer: Vec<(FailureLayer, String)>,
     /// Keep <COMMAND> alive.
     ///
     /// Run <COMMAND> on start, also restart it when it dies.
     #[structopt(short, long)]
     pub keep_alive: bool,
+    /// Run <COMMAND> only while network is reachable.
+    ///
+    /// Start <COMMAND> when network is up, stop it when network is down, restart it when it dies while up.
+    #[structopt(short, long, conflicts_with = "keep-alive")]
+    pub gate: bool,
+    /// Run <COMMAND> once when network goes down, not on every failed check.
+    ///
+    /// <COMMAND> gets `__PK_OUTAGE_SECONDS`.
+    #[structopt(long, conflicts_with_all = &["keep-alive", "gate"])]
+    pub edge: bool,
+    /// Command to run once when network is back, requires `--edge`.
+    ///
+    /// It gets `__PK_OUTAGE_SECONDS`.
+    #[structopt(
+        long,
+        name = "recovery-cmd",
+        value_name = "recovery-command",
+        requires = "edge"
+    )]
+    pub recovery_cmd: Option<String>,
+    /// Run <COMMAND> again every this amount of seconds while network is still down, requires `--edge`.
+    #[structopt(
+        long,
+        name = "repeat-every",
+        value_name = "repeat-seconds",
+        requires = "edge"
+    )]
+    pub repeat_every: Option<usize>,
+    /// Check network as soon as an interface goes down, default route changes or an address is removed. Linux only.
+    ///
+    /// <COMMAND> and hooks get `__PK_NETWORK_CHANGE`, like `link_down tun0`, or `overrun` if changes were lost.
+    #[structopt(long)]
+    pub netlink: bool,
+    /// Milliseconds without more network changes before checking, 1000 by default, requires `--netlink`.
+    #[structopt(
+        long,
+        name = "netlink-debounce",
+        value_name = "millis",
+        requires = "netlink"
+    )]
+    pub netlink_debounce: Option<u64>,
     /// Execution delay, in seconds.
     ///
     /// Seconds to check network for the first time after executing <COMMAND>.
-    #[structopt(short, long, name = "seconds", default_value = "5")]
+    #[structopt(
+        short,
+        long,
+        name = "wait-after-exec",
+        value_name = "seconds",
+        default_value = "5"
+    )]
     pub wait_after_exec: usize,
     /// Network check delay, in seconds.
     ///
     /// Check network again after this amount of seconds from the latest success.
-    #[structopt(short, long, name = "n", default_value = "5")]
+    #[structopt(
+        short,
+        long,
+        name = "network-every",
+        value_name = "n",
+        default_value = "5"
+    )]
     pub network_every: usize,
     /// Signal to kill <COMMAND>.
     ///
@@ -89,9 +234,266 @@ pub struct Opt {
     #[structopt(long)]
     pub kill_cmd: Option<String>,
 
+    /// Working directory of <COMMAND>.
+    #[structopt(long, name = "workdir", value_name = "dir", parse(from_os_str))]
+    pub workdir: Option<PathBuf>,
+    /// Set an environment variable for <COMMAND>, repeatable.
+    ///
+    /// <COMMAND> also gets `__PK_RESTARTS` and `__PK_REASON` (start, network_unreachable, child_exited).
+    /// Example: `--env LANG=C --env DEBUG=1`
+    #[structopt(long, name = "env", value_name = "KEY=VALUE", number_of_values = 1, parse(try_from_str = parse_key_value))]
+    pub env: Vec<(String, String)>,
+    /// Remove an inherited environment variable from <COMMAND>, repeatable.
+    #[structopt(long, name = "unset-env", value_name = "KEY", number_of_values = 1)]
+    pub unset_env: Vec<String>,
+    /// Run <COMMAND> with an empty environment, besides variables set.
+    #[structopt(long)]
+    pub clear_env: bool,
+    /// Load environment variables for <COMMAND> from a file of `KEY=VALUE` lines.
+    #[structopt(long, name = "env-file", value_name = "file", parse(from_os_str))]
+    pub env_file: Option<PathBuf>,
+
+    /// Run <COMMAND> as this user, name or uid.
+    #[structopt(long)]
+    pub user: Option<String>,
+    /// Run <COMMAND> with this group, name or gid. Defaults to the user's group.
+    #[structopt(long)]
+    pub group: Option<String>,
+    /// Comma separated supplementary groups of <COMMAND>. Defaults to the user's groups.
+    #[structopt(long)]
+    pub groups: Option<String>,
+    /// Run all hooks as this user, name or uid.
+    ///
+    /// Hooks: `--check-cmd`, `cmd()` checks of `--check-expr`, `--kill-cmd`, `--liveness-cmd`, `--output-hook`,
+    /// `--host-group` hooks, `--escalate` hooks, `--degraded-cmd`, `--on-layer` and `--recovery-cmd`.
+    #[structopt(long)]
+    pub hook_user: Option<String>,
+    /// Drop Pingkeeper's own privileges to this user after setup, name or uid.
+    ///
+    /// <COMMAND> and hooks run as this user too, unless they are allowed to switch user.
+    #[structopt(long, name = "drop-privileges", value_name = "drop-user")]
+    pub drop_privileges: Option<String>,
+    /// Comma separated capabilities kept after `--drop-privileges`, inherited by <COMMAND> and hooks.
+    ///
+    /// Linux only. Example: `--keep-caps CAP_NET_RAW` for `--use-ping`, `CAP_KILL` to signal other users.
+    #[structopt(
+        long,
+        name = "keep-caps",
+        value_name = "caps",
+        requires = "drop-privileges"
+    )]
+    pub keep_caps: Option<String>,
+
+    /// Maximum number of open files of <COMMAND>, `RLIMIT_NOFILE`.
+    #[structopt(long, name = "limit-nofile", value_name = "files")]
+    pub limit_nofile: Option<u64>,
+    /// Maximum virtual memory of <COMMAND>, `RLIMIT_AS`. Example: `512M`, `2G`.
+    #[structopt(long, name = "limit-as", value_name = "size", parse(try_from_str = parse_size))]
+    pub limit_as: Option<u64>,
+    /// Maximum CPU time of <COMMAND> in seconds, `RLIMIT_CPU`.
+    #[structopt(long, name = "limit-cpu", value_name = "cpu-seconds")]
+    pub limit_cpu: Option<u64>,
+    /// Run <COMMAND> in its own cgroup v2, created at this path. Linux only.
+    ///
+    /// Defaults to `/sys/fs/cgroup/pingkeeper-<pid>` when `--memory-max` or `--cpu-max` are set.
+    /// If the OOM killer kills <COMMAND>, its restart reason is `out_of_memory`.
+    #[structopt(long, parse(from_os_str))]
+    pub cgroup: Option<PathBuf>,
+    /// Maximum memory of <COMMAND>'s cgroup, `memory.max`. Example: `512M`, `2G`.
+    #[structopt(long, name = "memory-max", value_name = "bytes", parse(try_from_str = parse_size))]
+    pub memory_max: Option<u64>,
+    /// Maximum CPU of <COMMAND>'s cgroup, `cpu.max`, in percent of one CPU.
+    #[structopt(long, name = "cpu-max", value_name = "percent")]
+    pub cpu_max: Option<u32>,
+
+    /// Restart <COMMAND> when its resident memory exceeds this size. Example: `512M`, `2G`.
+    #[structopt(long, name = "max-rss", value_name = "rss", parse(try_from_str = parse_size))]
+    pub max_rss: Option<u64>,
+    /// Restart <COMMAND> when its CPU usage exceeds this percent of one CPU.
+    #[structopt(long, name = "max-cpu", value_name = "cpu")]
+    pub max_cpu: Option<u32>,
+    /// Restart <COMMAND> when its open file descriptors exceed this number.
+    #[structopt(long, name = "max-fds", value_name = "fds")]
+    pub max_fds: Option<usize>,
+    /// Restart <COMMAND> when its threads exceed this number.
+    #[structopt(long, name = "max-threads", value_name = "threads")]
+    pub max_threads: Option<usize>,
+    /// Resource usage check delay, in seconds.
+    ///
+    /// Sample <COMMAND> usage from `/proc/<pid>` every this amount of seconds.
+    /// Only used with `--max-rss`, `--max-cpu`, `--max-fds` or `--max-threads`.
+    #[structopt(
+        long,
+        name = "usage-every",
+        value_name = "usage-seconds",
+        default_value = "5"
+    )]
+    pub usage_every: usize,
+    /// Restart <COMMAND> only after a threshold is exceeded for this amount of seconds.
+    #[structopt(
+        long,
+        name = "exceeded-for",
+        value_name = "exceeded-seconds",
+        default_value = "0"
+    )]
+    pub exceeded_for: usize,
+
+    /// Liveness probe of <COMMAND>, TCP connection to `host:port`.
+    ///
+    /// Independent from the network check, a failing liveness probe restarts <COMMAND> only.
+    #[structopt(long, name = "liveness-tcp", value_name = "address", conflicts_with_all = &["liveness-http", "liveness-cmd"])]
+    pub liveness_tcp: Option<String>,
+    /// Liveness probe of <COMMAND>, HTTP `GET` to an `http://` url, fails if not 2xx or 3xx.
+    #[structopt(
+        long,
+        name = "liveness-http",
+        value_name = "url",
+        conflicts_with = "liveness-cmd"
+    )]
+    pub liveness_http: Option<String>,
+    /// Liveness probe of <COMMAND>, custom command, fails if exit status is not 0 or after `--timeout`.
+    #[structopt(long, name = "liveness-cmd", value_name = "liveness-command")]
+    pub liveness_cmd: Option<String>,
+    /// Liveness probe delay, in seconds.
+    #[structopt(
+        long,
+        name = "liveness-every",
+        value_name = "liveness-seconds",
+        default_value = "10"
+    )]
+    pub liveness_every: usize,
+    /// Liveness probe failures in a row to restart <COMMAND>.
+    #[structopt(
+        long,
+        name = "liveness-threshold",
+        value_name = "failures",
+        default_value = "3"
+    )]
+    pub liveness_threshold: usize,
+    /// Seconds without liveness probe after executing <COMMAND>.
+    #[structopt(
+        long,
+        name = "liveness-grace",
+        value_name = "grace-seconds",
+        default_value = "30"
+    )]
+    pub liveness_grace: usize,
+
+    /// <COMMAND> is ready when a TCP connection to `host:port` succeeds.
+    ///
+    /// Network checks wait for readiness instead of `--wait-after-exec`.
+    #[structopt(long, name = "ready-tcp", value_name = "ready-address", conflicts_with_all = &["ready-file", "ready-interface", "ready-log", "ready-notify"])]
+    pub ready_tcp: Option<String>,
+    /// <COMMAND> is ready when this file exists.
+    #[structopt(long, name = "ready-file", value_name = "ready-path", parse(from_os_str), conflicts_with_all = &["ready-interface", "ready-log", "ready-notify"])]
+    pub ready_file: Option<PathBuf>,
+    /// <COMMAND> is ready when this network interface is up.
+    #[structopt(long, name = "ready-interface", value_name = "interface", conflicts_with_all = &["ready-log", "ready-notify"])]
+    pub ready_interface: Option<String>,
+    /// <COMMAND> is ready when a line of its output matches this regex.
+    #[structopt(long, name = "ready-log", value_name = "regex", parse(try_from_str = Regex::new), conflicts_with = "ready-notify")]
+    pub ready_log: Option<Regex>,
+    /// <COMMAND> is ready when it sends `READY=1` to the socket at `NOTIFY_SOCKET`, like sd_notify.
+    #[structopt(long)]
+    pub ready_notify: bool,
+    /// Seconds for <COMMAND> to be ready, a failed start after them.
+    #[structopt(
+        long,
+        name = "ready-timeout",
+        value_name = "ready-seconds",
+        default_value = "60"
+    )]
+    pub ready_timeout: usize,
+
+    /// Rule for output of <COMMAND>, `ACTION:REGEX`. Many allowed.
+    ///
+    /// Actions: `restart`, `kill`, `hook` (runs `--output-hook`) or `warn`.
+    /// Example: `--on-output "restart:AUTH_FAILED|Inactivity timeout"`
+    #[structopt(
+        long,
+        name = "on-output",
+        value_name = "ACTION:REGEX",
+        number_of_values = 1
+    )]
+    pub on_output: Vec<OutputRule>,
+    /// Command for `hook` output rules. It gets `__PK_PID`, `__PK_RULE` and `__PK_MATCH`.
+    #[structopt(long, name = "output-hook", value_name = "hook-command")]
+    pub output_hook: Option<String>,
+    /// Minimum seconds between actions of the same output rule.
+    #[structopt(
+        long,
+        name = "output-cooldown",
+        value_name = "cooldown-seconds",
+        default_value = "60"
+    )]
+    pub output_cooldown: usize,
+
+    /// When to restart <COMMAND> after it exits on its own, like systemd's `Restart=`.
+    ///
+    /// Only used by `--keep-alive` and `--gate`, Pingkeeper exits when <COMMAND> is not restarted.
+    #[structopt(long, name = "restart", value_name = "policy", default_value = "always", possible_values = &["always", "on-failure", "on-abnormal", "on-signal", "never"])]
+    pub restart: RestartPolicy,
+    /// Exit status codes of <COMMAND> that are clean besides 0, comma separated.
+    #[structopt(
+        long,
+        name = "success-exit-status",
+        value_name = "codes",
+        use_delimiter = true
+    )]
+    pub success_exit_status: Vec<i32>,
+    /// Exit status codes of <COMMAND> to give up on, comma separated.
+    #[structopt(
+        long,
+        name = "fatal-exit-status",
+        value_name = "fatal-codes",
+        use_delimiter = true
+    )]
+    pub fatal_exit_status: Vec<i32>,
+
+    /// Pidfile of an already running <COMMAND> to adopt at start, instead of spawning another one.
+    ///
+    /// Pingkeeper writes there the pid of each <COMMAND> it spawns, so a restarted Pingkeeper takes over, and removes
+    /// it when stopped. The process must be running <COMMAND>, or match `--adopt-cmdline`. An adopted <COMMAND> is
+    /// monitored and signaled through a pidfd on Linux, its exit status is unknown.
+    #[structopt(
+        long,
+        name = "adopt-pidfile",
+        value_name = "pidfile",
+        parse(from_os_str)
+    )]
+    pub adopt_pidfile: Option<PathBuf>,
+    /// Pid of an already running <COMMAND> to adopt at start, over `--adopt-pidfile`.
+    ///
+    /// The process must be running <COMMAND>, or match `--adopt-cmdline`.
+    #[structopt(long, name = "adopt-pid", value_name = "pid")]
+    pub adopt_pid: Option<u32>,
+    /// Command line, arguments joined with spaces, that the process to adopt must match instead of <COMMAND>.
+    ///
+    /// Without a pid, the first running process matching it is adopted.
+    #[structopt(long, name = "adopt-cmdline", value_name = "cmdline-regex", parse(try_from_str = Regex::new))]
+    pub adopt_cmdline: Option<Regex>,
+
+    /// Step of an escalation ladder for failed checks, repeatable and in order; the last one repeats.
+    ///
+    /// Steps: `restart[:N]` runs or restarts <COMMAND>, `hook:N:COMMAND` runs a command instead (it gets
+    /// `__PK_ESCALATION_STEP`), `exit` gives up. N is the number of restarts or hook runs for the step, 1 by default.
+    /// Example: `--escalate restart:2 --escalate "hook:1:ip link set tun0 up" --escalate exit`.
+    /// Conflicts with `--edge`, `--gate` and `--on-layer`.
+    #[structopt(long, name = "escalate", value_name = "STEP", number_of_values = 1, conflicts_with_all = &["edge", "gate", "on-layer"])]
+    pub escalate: Vec<EscalationStep>,
+    /// Seconds of reachable network to go back to the first escalation step.
+    #[structopt(
+        long,
+        name = "stable-for",
+        value_name = "stable-seconds",
+        default_value = "300"
+    )]
+    pub stable_for: usize,
+
     /// Maximum number of <COMMAND> errors in a row.
     ///
-    /// 0 for infinite. Only used by `--keep-alive`.
+    /// 0 for infinite. Errors: exits of <COMMAND> with `--keep-alive` or while `--gate` is open, readiness timeouts and
+    /// failures to get its status.
     #[structopt(short, long, default_value = "0")]
     pub max_errors: usize,
 
@@ -105,3 +507,64 @@ pub struct Opt {
     #[structopt(short, long)]
     pub quiet: bool,
 }
+
+/// Parses a `KEY=VALUE` pair
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if !key.is_empty() => Ok((String::from(key), String::from(value))),
+        _ => Err(format!("invalid KEY=VALUE: `{}`", s)),
+    }
+}
+
+/// Parses `LAYER:COMMAND`, like `link:ip link set tun0 up`
+fn parse_layer_command(s: &str) -> Result<(FailureLayer, String), String> {
+    let mut parts = s.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(layer), Some(command)) if !command.is_empty() => {
+            Ok((layer.parse()?, String::from(command)))
+        }
+        _ => Err(format!("invalid LAYER:COMMAND: `{}`", s)),
+    }
+}
+
+/// Parses a size in bytes, with an optional `K`, `M` or `G` suffix
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (number, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1024),
+        Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size: `{}`", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("2k"), Ok(2048));
+        assert_eq!(parse_size("64M"), Ok(64 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Ok(1024 * 1024 * 1024));
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("99999999999G").is_err());
+    }
+    #[test]
+    fn layer_command() {
+        assert_eq!(
+            parse_layer_command("link:ip link set tun0 up"),
+            Ok((FailureLayer::Link, String::from("ip link set tun0 up")))
+        );
+        assert!(parse_layer_command("dns:").is_err());
+        assert!(parse_layer_command("physical:reboot").is_err());
+    }
+}
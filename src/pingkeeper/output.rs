@@ -0,0 +1,167 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::Child;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Output stream of a child process
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stream::Stdout => write!(f, "stdout"),
+            Stream::Stderr => write!(f, "stderr"),
+        }
+    }
+}
+
+/// A line of output of a child process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputLine {
+    pub stream: Stream,
+    pub line: String,
+}
+
+/// Reads output of a child process line by line, echoing it unless quiet
+#[derive(Debug)]
+pub struct OutputCapture {
+    receiver: Receiver<OutputLine>,
+}
+
+impl OutputCapture {
+    /// Starts reading piped stdout and stderr of a child process
+    pub fn start(child: &mut Child, quiet: bool) -> Self {
+        let (sender, receiver) = channel();
+        if let Some(stdout) = child.stdout.take() {
+            read_lines(stdout, Stream::Stdout, quiet, sender.clone());
+        }
+        if let Some(stderr) = child.stderr.take() {
+            read_lines(stderr, Stream::Stderr, quiet, sender);
+        }
+        OutputCapture { receiver }
+    }
+    /// Gets lines read since last call, without waiting
+    pub fn lines(&self) -> Vec<OutputLine> {
+        self.receiver.try_iter().collect()
+    }
+    /// Gets lines read since last call, waiting until the streams are closed or the timeout
+    pub fn drain(&self, timeout: Duration) -> Vec<OutputLine> {
+        let deadline = Instant::now() + timeout;
+        let mut lines = Vec::new();
+        while let Ok(line) = self
+            .receiver
+            .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        {
+            lines.push(line);
+        }
+        lines
+    }
+}
+
+/// Reads lines in a thread until the stream is closed
+fn read_lines<R: Read + Send + 'static>(
+    stream: R,
+    kind: Stream,
+    quiet: bool,
+    sender: Sender<OutputLine>,
+) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+        let mut buffer = Vec::new();
+        while let Ok(n) = reader.read_until(b'\n', &mut buffer) {
+            if n == 0 {
+                break;
+            }
+            if !quiet {
+                match kind {
+                    Stream::Stdout => io::stdout().write_all(&buffer).ok(),
+                    Stream::Stderr => io::stderr().write_all(&buffer).ok(),
+                };
+            }
+            let line = String::from_utf8_lossy(&buffer)
+                .trim_end_matches(&['\r', '\n'][..])
+                .to_string();
+            // keep reading even if nobody listens
+            sender.send(OutputLine { stream: kind, line }).ok();
+            buffer.clear();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::{Command, Stdio};
+
+    #[test]
+    fn capture() {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg("echo out; echo err >&2; printf last")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let output = OutputCapture::start(&mut child, true);
+        child.wait().unwrap();
+        thread::sleep(std::time::Duration::from_millis(50));
+        let mut lines = output.lines();
+        lines.sort_by(|a, b| a.line.cmp(&b.line));
+        assert_eq!(
+            lines,
+            vec![
+                OutputLine {
+                    stream: Stream::Stderr,
+                    line: String::from("err")
+                },
+                OutputLine {
+                    stream: Stream::Stdout,
+                    line: String::from("last")
+                },
+                OutputLine {
+                    stream: Stream::Stdout,
+                    line: String::from("out")
+                },
+            ]
+        );
+        assert!(output.lines().is_empty());
+    }
+    #[test]
+    fn drain() {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg("echo AUTH_FAILED; exit 1")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let output = OutputCapture::start(&mut child, true);
+        child.wait().unwrap();
+        let lines = output.drain(std::time::Duration::from_secs(5));
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].line, "AUTH_FAILED");
+    }
+}
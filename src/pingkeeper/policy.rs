@@ -0,0 +1,205 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use nix::sys::signal::Signal;
+use std::convert::TryFrom;
+use std::fmt;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::str::FromStr;
+
+/// When to restart a child process that exited on its own, like systemd's `Restart=`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Always
+    Always,
+    /// Unclean exit status, unclean signal or unknown status
+    OnFailure,
+    /// Unclean signal, or unknown status
+    OnAbnormal,
+    /// Unclean signal
+    OnSignal,
+    /// Never
+    Never,
+}
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestartPolicy::Always => write!(f, "always"),
+            RestartPolicy::OnFailure => write!(f, "on-failure"),
+            RestartPolicy::OnAbnormal => write!(f, "on-abnormal"),
+            RestartPolicy::OnSignal => write!(f, "on-signal"),
+            RestartPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+impl FromStr for RestartPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(RestartPolicy::Always),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "on-abnormal" => Ok(RestartPolicy::OnAbnormal),
+            "on-signal" => Ok(RestartPolicy::OnSignal),
+            "never" => Ok(RestartPolicy::Never),
+            _ => Err(format!("unknown restart policy: `{}`", s)),
+        }
+    }
+}
+
+/// What to do after a child process exited on its own
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExitDecision {
+    Restart,
+    Stop,
+    Fatal,
+}
+
+/// Restart policy with exit status codes that are success or fatal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitPolicy {
+    pub restart: RestartPolicy,
+    /// Clean exit status codes besides 0
+    pub success: Vec<i32>,
+    /// Exit status codes to give up on
+    pub fatal: Vec<i32>,
+}
+
+impl Default for ExitPolicy {
+    fn default() -> Self {
+        ExitPolicy {
+            restart: RestartPolicy::Always,
+            success: Vec::new(),
+            fatal: Vec::new(),
+        }
+    }
+}
+
+impl ExitPolicy {
+    /// Decides after an exit, with its status if known
+    pub fn decide(&self, status: Option<ExitStatus>) -> ExitDecision {
+        let code = status.and_then(|status| status.code());
+        if code.is_some_and(|code| self.fatal.contains(&code)) {
+            return ExitDecision::Fatal;
+        }
+        let clean_code = code.map(|code| code == 0 || self.success.contains(&code));
+        let unclean_signal = status
+            .and_then(|status| status.signal())
+            .is_some_and(|signal| !is_clean_signal(signal));
+        let restart = match self.restart {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => {
+                clean_code == Some(false) || unclean_signal || status.is_none()
+            }
+            RestartPolicy::OnAbnormal => status.is_none() || unclean_signal,
+            RestartPolicy::OnSignal => unclean_signal,
+            RestartPolicy::Never => false,
+        };
+        if restart {
+            ExitDecision::Restart
+        } else {
+            ExitDecision::Stop
+        }
+    }
+}
+
+/// Clean signals, as systemd: SIGHUP, SIGINT, SIGTERM and SIGPIPE
+fn is_clean_signal(signal: i32) -> bool {
+    matches!(
+        Signal::try_from(signal),
+        Ok(Signal::SIGHUP) | Ok(Signal::SIGINT) | Ok(Signal::SIGTERM) | Ok(Signal::SIGPIPE)
+    )
+}
+
+/// Environment variables of an exit status, `__PK_EXIT_STATUS` and `__PK_EXIT_SIGNAL`
+pub fn exit_env(status: Option<ExitStatus>) -> Vec<(&'static str, String)> {
+    let code = status.and_then(|status| status.code());
+    let signal =
+        status
+            .and_then(|status| status.signal())
+            .map(|signal| match Signal::try_from(signal) {
+                Ok(signal) => signal.to_string(),
+                Err(_) => signal.to_string(),
+            });
+    vec![
+        (
+            "__PK_EXIT_STATUS",
+            code.map(|code| code.to_string()).unwrap_or_default(),
+        ),
+        ("__PK_EXIT_SIGNAL", signal.unwrap_or_default()),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exited(code: i32) -> Option<ExitStatus> {
+        Some(ExitStatus::from_raw(code << 8))
+    }
+    fn killed(signal: Signal) -> Option<ExitStatus> {
+        Some(ExitStatus::from_raw(signal as i32))
+    }
+
+    #[test]
+    fn policies() {
+        let policy = |restart| ExitPolicy {
+            restart,
+            success: vec![2],
+            fatal: vec![78],
+        };
+        let restarts = |restart, status| policy(restart).decide(status) == ExitDecision::Restart;
+        assert!(restarts(RestartPolicy::Always, exited(0)));
+        assert!(!restarts(RestartPolicy::Never, exited(1)));
+        assert!(!restarts(RestartPolicy::OnFailure, exited(0)));
+        assert!(!restarts(RestartPolicy::OnFailure, exited(2)));
+        assert!(restarts(RestartPolicy::OnFailure, exited(1)));
+        assert!(restarts(RestartPolicy::OnFailure, killed(Signal::SIGSEGV)));
+        assert!(restarts(RestartPolicy::OnFailure, killed(Signal::SIGKILL)));
+        assert!(!restarts(RestartPolicy::OnFailure, killed(Signal::SIGTERM)));
+        assert!(!restarts(RestartPolicy::OnFailure, killed(Signal::SIGHUP)));
+        assert!(!restarts(RestartPolicy::OnAbnormal, exited(1)));
+        assert!(restarts(RestartPolicy::OnAbnormal, None));
+        assert!(restarts(RestartPolicy::OnSignal, killed(Signal::SIGKILL)));
+        assert!(!restarts(RestartPolicy::OnSignal, killed(Signal::SIGTERM)));
+        assert_eq!(
+            policy(RestartPolicy::Always).decide(exited(78)),
+            ExitDecision::Fatal
+        );
+    }
+    #[test]
+    fn env() {
+        assert_eq!(
+            exit_env(exited(3)),
+            vec![
+                ("__PK_EXIT_STATUS", String::from("3")),
+                ("__PK_EXIT_SIGNAL", String::new())
+            ]
+        );
+        assert_eq!(
+            exit_env(killed(Signal::SIGKILL))[1],
+            ("__PK_EXIT_SIGNAL", String::from("SIGKILL"))
+        );
+        assert_eq!(exit_env(None)[0], ("__PK_EXIT_STATUS", String::new()));
+    }
+    #[test]
+    fn parse() {
+        assert_eq!("on-abnormal".parse(), Ok(RestartPolicy::OnAbnormal));
+        assert!("sometimes".parse::<RestartPolicy>().is_err());
+    }
+}
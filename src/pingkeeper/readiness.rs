@@ -0,0 +1,293 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use nix::errno::Errno;
+use nix::sys::stat::Mode;
+use nix::unistd::{chown, mkdir};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use regex::Regex;
+use std::fs;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use super::credentials::Credentials;
+use super::network_monitor::interface;
+use super::output::OutputLine;
+
+/// When is the child process ready
+#[derive(Debug, Clone)]
+pub enum ReadinessCheck {
+    /// TCP connection to an address with port succeeds
+    Tcp(String),
+    /// File exists
+    File(PathBuf),
+    /// Network interface is up
+    Interface(String),
+    /// A line of output matches
+    Log(Regex),
+    /// Child process sends `READY=1` to the socket at `NOTIFY_SOCKET`, like sd_notify
+    Notify,
+}
+
+/// Readiness of the child process, instead of a fixed wait after spawning it
+#[derive(Debug, Clone)]
+pub struct Readiness {
+    check: ReadinessCheck,
+    timeout: Duration,
+}
+
+impl Readiness {
+    /// Creates a readiness check, 60 seconds of timeout
+    pub fn new(check: ReadinessCheck) -> Self {
+        Readiness {
+            check,
+            timeout: Duration::from_secs(60),
+        }
+    }
+    /// Sets time to be ready, a failed start after it
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Does the check need the output of the child process?
+    pub fn needs_output(&self) -> bool {
+        matches!(self.check, ReadinessCheck::Log(_))
+    }
+}
+
+/// Readiness state of the child process
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ReadyState {
+    Waiting,
+    Ready,
+    TimedOut,
+}
+
+/// Waits for the child process to be ready, from spawn until timeout
+#[derive(Debug)]
+pub struct ReadinessMonitor {
+    readiness: Readiness,
+    started: Instant,
+    socket: Option<(UnixDatagram, PathBuf)>,
+}
+
+impl ReadinessMonitor {
+    /// Creates a monitor, binding a socket for `Notify` owned by the child process credentials
+    pub fn new(readiness: Readiness, credentials: &Credentials) -> io::Result<Self> {
+        let socket = match readiness.check {
+            ReadinessCheck::Notify => Some(notify_socket(credentials)?),
+            _ => None,
+        };
+        Ok(ReadinessMonitor {
+            readiness,
+            started: Instant::now(),
+            socket,
+        })
+    }
+    /// Gets path of the socket for `NOTIFY_SOCKET`, if any
+    pub fn notify_socket(&self) -> Option<&PathBuf> {
+        self.socket.as_ref().map(|(_, path)| path)
+    }
+    /// Starts over, for a new child process
+    pub fn reset(&mut self) {
+        self.started = Instant::now();
+        // drop old notifications
+        if let Some((socket, _)) = &self.socket {
+            let mut buffer = [0; 1024];
+            while socket.recv(&mut buffer).is_ok() {}
+        }
+    }
+    /// Checks readiness, with output lines since previous poll
+    pub fn poll(&mut self, lines: &[OutputLine]) -> ReadyState {
+        let ready = match &self.readiness.check {
+            ReadinessCheck::Tcp(address) => address
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .is_some_and(|addr| {
+                    TcpStream::connect_timeout(&addr, Duration::from_millis(100)).is_ok()
+                }),
+            ReadinessCheck::File(path) => path.exists(),
+            ReadinessCheck::Interface(name) => interface::is_up(name),
+            ReadinessCheck::Log(regex) => lines.iter().any(|line| regex.is_match(&line.line)),
+            ReadinessCheck::Notify => self.socket.as_ref().is_some_and(|(socket, _)| {
+                let mut buffer = [0; 4096];
+                let mut ready = false;
+                while let Ok(n) = socket.recv(&mut buffer) {
+                    ready |= is_ready_message(&String::from_utf8_lossy(&buffer[..n]));
+                }
+                ready
+            }),
+        };
+        if ready {
+            ReadyState::Ready
+        } else if self.started.elapsed() >= self.readiness.timeout {
+            ReadyState::TimedOut
+        } else {
+            ReadyState::Waiting
+        }
+    }
+}
+
+impl Drop for ReadinessMonitor {
+    fn drop(&mut self) {
+        if let Some((_, path)) = &self.socket {
+            fs::remove_file(path).ok();
+            if let Some(dir) = path.parent() {
+                fs::remove_dir(dir).ok();
+            }
+        }
+    }
+}
+
+/// Binds a non blocking datagram socket in a private temporary directory
+///
+/// Directory and socket are only accessible by the owner, the child process user if set.
+fn notify_socket(credentials: &Credentials) -> io::Result<(UnixDatagram, PathBuf)> {
+    let dir = private_dir()?;
+    let path = dir.join("notify");
+    let bind = || -> io::Result<UnixDatagram> {
+        let socket = UnixDatagram::bind(&path)?;
+        socket.set_nonblocking(true)?;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        let (uid, gid) = credentials.owner();
+        if uid.is_some() || gid.is_some() {
+            chown(&dir, uid, gid)?;
+            chown(&path, uid, gid)?;
+        }
+        Ok(socket)
+    };
+    match bind() {
+        Ok(socket) => Ok((socket, path)),
+        Err(err) => {
+            fs::remove_file(&path).ok();
+            fs::remove_dir(&dir).ok();
+            Err(err)
+        }
+    }
+}
+
+/// Creates a new directory in the temporary directory, only accessible by its owner, like `mkdtemp`
+fn private_dir() -> io::Result<PathBuf> {
+    loop {
+        let name: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(12)
+            .collect();
+        let dir = std::env::temp_dir().join(format!("pingkeeper-{}", name));
+        match mkdir(&dir, Mode::S_IRWXU) {
+            Ok(()) => {
+                fs::set_permissions(&dir, fs::Permissions::from_mode(0o700))?;
+                return Ok(dir);
+            }
+            Err(Errno::EEXIST) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Is there a `READY=1` line in a notification?
+fn is_ready_message(message: &str) -> bool {
+    message.lines().any(|line| line.trim() == "READY=1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::output::Stream;
+    use super::*;
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("pk-ready-{}", std::process::id()));
+        fs::remove_file(&path).ok();
+        let mut readiness = ReadinessMonitor::new(
+            Readiness::new(ReadinessCheck::File(path.clone())),
+            &Credentials::default(),
+        )
+        .unwrap();
+        assert_eq!(readiness.poll(&[]), ReadyState::Waiting);
+        fs::write(&path, "").unwrap();
+        assert_eq!(readiness.poll(&[]), ReadyState::Ready);
+        fs::remove_file(&path).ok();
+    }
+    #[test]
+    fn log_and_timeout() {
+        let readiness = Readiness::new(ReadinessCheck::Log(
+            Regex::new("Initialization Sequence Completed").unwrap(),
+        ))
+        .timeout(Duration::from_millis(0));
+        assert!(readiness.needs_output());
+        let mut readiness = ReadinessMonitor::new(readiness, &Credentials::default()).unwrap();
+        let line = |line: &str| OutputLine {
+            stream: Stream::Stdout,
+            line: String::from(line),
+        };
+        assert_eq!(readiness.poll(&[line("Connecting")]), ReadyState::TimedOut);
+        assert_eq!(
+            readiness.poll(&[
+                line("Connecting"),
+                line("... Initialization Sequence Completed")
+            ]),
+            ReadyState::Ready
+        );
+    }
+    #[test]
+    fn notify() {
+        let mut readiness = ReadinessMonitor::new(
+            Readiness::new(ReadinessCheck::Notify),
+            &Credentials::default(),
+        )
+        .unwrap();
+        let path = readiness.notify_socket().unwrap().clone();
+        // private directory, not writable by others
+        let dir = path.parent().unwrap().to_path_buf();
+        let mode = |path: &PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&path), 0o600);
+        assert_eq!(readiness.poll(&[]), ReadyState::Waiting);
+        let client = UnixDatagram::unbound().unwrap();
+        client.send_to(b"STATUS=starting\n", &path).unwrap();
+        assert_eq!(readiness.poll(&[]), ReadyState::Waiting);
+        client.send_to(b"STATUS=done\nREADY=1\n", &path).unwrap();
+        assert_eq!(readiness.poll(&[]), ReadyState::Ready);
+        drop(readiness);
+        assert!(!path.exists());
+        assert!(!dir.exists());
+    }
+    #[test]
+    fn notify_owner() {
+        use nix::unistd::Uid;
+        use std::os::unix::fs::MetadataExt;
+        // Only root can give the socket to another user
+        if !Uid::current().is_root() {
+            return;
+        }
+        let credentials = Credentials::new(Some("nobody"), None, None).unwrap();
+        let readiness =
+            ReadinessMonitor::new(Readiness::new(ReadinessCheck::Notify), &credentials).unwrap();
+        let path = readiness.notify_socket().unwrap();
+        let nobody = credentials.owner().0.unwrap().as_raw();
+        assert_eq!(fs::metadata(path).unwrap().uid(), nobody);
+        assert_eq!(fs::metadata(path.parent().unwrap()).unwrap().uid(), nobody);
+    }
+}
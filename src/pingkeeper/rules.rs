@@ -0,0 +1,191 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use super::output::OutputLine;
+
+/// What to do when output matches a rule
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Kill and spawn child process again
+    Restart,
+    /// Kill child process
+    Kill,
+    /// Run output hook command
+    Hook,
+    /// Log a warning
+    Warn,
+}
+impl fmt::Display for RuleAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuleAction::Restart => write!(f, "restart"),
+            RuleAction::Kill => write!(f, "kill"),
+            RuleAction::Hook => write!(f, "hook"),
+            RuleAction::Warn => write!(f, "warn"),
+        }
+    }
+}
+impl FromStr for RuleAction {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restart" => Ok(RuleAction::Restart),
+            "kill" => Ok(RuleAction::Kill),
+            "hook" => Ok(RuleAction::Hook),
+            "warn" => Ok(RuleAction::Warn),
+            _ => Err(format!("unknown action: `{}`", s)),
+        }
+    }
+}
+
+/// A regex to watch for in output of child process, and its action
+#[derive(Debug, Clone)]
+pub struct OutputRule {
+    pub regex: Regex,
+    pub action: RuleAction,
+}
+
+impl OutputRule {
+    pub fn new(regex: Regex, action: RuleAction) -> Self {
+        OutputRule { regex, action }
+    }
+}
+
+/// Parses `ACTION:REGEX`, like `restart:AUTH_FAILED`
+impl FromStr for OutputRule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let action = parts.next().unwrap_or_default().parse()?;
+        let regex = parts
+            .next()
+            .ok_or_else(|| format!("no `ACTION:REGEX` in `{}`", s))?;
+        let regex = Regex::new(regex).map_err(|err| err.to_string())?;
+        Ok(OutputRule { regex, action })
+    }
+}
+
+/// A rule matched by a line of output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleMatch {
+    pub rule: String,
+    pub action: RuleAction,
+    pub line: String,
+}
+
+/// Matches output against rules, each rule at most once every cooldown
+#[derive(Debug)]
+pub struct RuleMonitor {
+    rules: Vec<(OutputRule, Option<Instant>)>,
+    cooldown: Duration,
+}
+
+impl RuleMonitor {
+    pub fn new(rules: Vec<OutputRule>, cooldown: Duration) -> Self {
+        RuleMonitor {
+            rules: rules.into_iter().map(|rule| (rule, None)).collect(),
+            cooldown,
+        }
+    }
+    /// Gets rules matched by these lines, first matching line per rule
+    pub fn check(&mut self, lines: &[OutputLine]) -> Vec<RuleMatch> {
+        let now = Instant::now();
+        let cooldown = self.cooldown;
+        let mut matches = Vec::new();
+        for (rule, last) in &mut self.rules {
+            if last.is_some_and(|last| now.duration_since(last) < cooldown) {
+                continue;
+            }
+            if let Some(line) = lines.iter().find(|line| rule.regex.is_match(&line.line)) {
+                *last = Some(now);
+                matches.push(RuleMatch {
+                    rule: String::from(rule.regex.as_str()),
+                    action: rule.action,
+                    line: line.line.clone(),
+                });
+            }
+        }
+        matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::output::Stream;
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<OutputLine> {
+        lines
+            .iter()
+            .map(|line| OutputLine {
+                stream: Stream::Stderr,
+                line: String::from(*line),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse() {
+        let rule: OutputRule = "restart:AUTH_FAILED|Inactivity timeout".parse().unwrap();
+        assert_eq!(rule.action, RuleAction::Restart);
+        assert_eq!(rule.regex.as_str(), "AUTH_FAILED|Inactivity timeout");
+        let rule: OutputRule = "warn:a:b".parse().unwrap();
+        assert_eq!(rule.regex.as_str(), "a:b");
+        assert!("reboot:AUTH_FAILED".parse::<OutputRule>().is_err());
+        assert!("restart".parse::<OutputRule>().is_err());
+        assert!("restart:(".parse::<OutputRule>().is_err());
+    }
+    #[test]
+    fn cooldown() {
+        let rules = vec![
+            "restart:AUTH_FAILED".parse().unwrap(),
+            "warn:timeout".parse().unwrap(),
+        ];
+        let mut monitor = RuleMonitor::new(rules, Duration::from_secs(60));
+        assert!(monitor.check(&lines(&["connecting"])).is_empty());
+        let matches = monitor.check(&lines(&["AUTH_FAILED 1", "AUTH_FAILED 2", "timeout"]));
+        assert_eq!(
+            matches,
+            vec![
+                RuleMatch {
+                    rule: String::from("AUTH_FAILED"),
+                    action: RuleAction::Restart,
+                    line: String::from("AUTH_FAILED 1"),
+                },
+                RuleMatch {
+                    rule: String::from("timeout"),
+                    action: RuleAction::Warn,
+                    line: String::from("timeout"),
+                },
+            ]
+        );
+        // burst
+        assert!(monitor.check(&lines(&["AUTH_FAILED 3"])).is_empty());
+        let mut monitor = RuleMonitor::new(
+            vec!["kill:AUTH_FAILED".parse().unwrap()],
+            Duration::from_secs(0),
+        );
+        assert_eq!(monitor.check(&lines(&["AUTH_FAILED"])).len(), 1);
+        assert_eq!(monitor.check(&lines(&["AUTH_FAILED"])).len(), 1);
+    }
+}
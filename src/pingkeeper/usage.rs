@@ -0,0 +1,279 @@
+/*
+    Pingkeeper
+    Copyright (C) 2020  Ignacio Lago
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use nix::unistd::{sysconf, SysconfVar};
+use std::fmt;
+use std::fs;
+use std::time::{Duration, Instant};
+
+/// Resource usage of a process, sampled from `/proc/<pid>`
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Resident memory in bytes
+    pub rss: u64,
+    /// CPU usage since previous sample, in percent of one CPU
+    pub cpu: u32,
+    /// Open file descriptors
+    pub fds: usize,
+    /// Threads
+    pub threads: usize,
+}
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "rss={}KiB cpu={}% fds={} threads={}",
+            self.rss / 1024,
+            self.cpu,
+            self.fds,
+            self.threads
+        )
+    }
+}
+
+/// Resource usage metrics
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Metric {
+    Rss,
+    Cpu,
+    Fds,
+    Threads,
+}
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Metric::Rss => write!(f, "rss"),
+            Metric::Cpu => write!(f, "cpu"),
+            Metric::Fds => write!(f, "fds"),
+            Metric::Threads => write!(f, "threads"),
+        }
+    }
+}
+
+/// Maximum resource usage of a process
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct Thresholds {
+    /// Maximum resident memory in bytes
+    pub rss: Option<u64>,
+    /// Maximum CPU usage, in percent of one CPU
+    pub cpu: Option<u32>,
+    /// Maximum open file descriptors
+    pub fds: Option<usize>,
+    /// Maximum threads
+    pub threads: Option<usize>,
+}
+
+impl Thresholds {
+    /// Does not limit anything?
+    pub fn is_empty(&self) -> bool {
+        self.rss.is_none() && self.cpu.is_none() && self.fds.is_none() && self.threads.is_none()
+    }
+    /// Gets metrics over their threshold
+    pub fn exceeded(&self, usage: &Usage) -> Vec<Metric> {
+        let mut metrics = Vec::new();
+        if self.rss.is_some_and(|max| usage.rss > max) {
+            metrics.push(Metric::Rss);
+        }
+        if self.cpu.is_some_and(|max| usage.cpu > max) {
+            metrics.push(Metric::Cpu);
+        }
+        if self.fds.is_some_and(|max| usage.fds > max) {
+            metrics.push(Metric::Fds);
+        }
+        if self.threads.is_some_and(|max| usage.threads > max) {
+            metrics.push(Metric::Threads);
+        }
+        metrics
+    }
+}
+
+/// Samples resource usage of a process and tells when a threshold is exceeded for a while
+#[derive(Debug)]
+pub struct UsageMonitor {
+    thresholds: Thresholds,
+    exceeded_for: Duration,
+    pid: Option<u32>,
+    cpu_ticks: Option<(u64, Instant)>,
+    since: Vec<(Metric, Instant)>,
+}
+
+impl UsageMonitor {
+    pub fn new(thresholds: Thresholds, exceeded_for: Duration) -> Self {
+        UsageMonitor {
+            thresholds,
+            exceeded_for,
+            pid: None,
+            cpu_ticks: None,
+            since: Vec::new(),
+        }
+    }
+    /// Samples process and gets the first metric over its threshold for long enough
+    pub fn check(&mut self, pid: u32) -> Option<(Metric, Usage)> {
+        let usage = self.sample(pid)?;
+        let now = Instant::now();
+        let exceeded = self.thresholds.exceeded(&usage);
+        self.since.retain(|(metric, _)| exceeded.contains(metric));
+        for metric in exceeded {
+            if !self.since.iter().any(|(m, _)| *m == metric) {
+                self.since.push((metric, now));
+            }
+        }
+        self.since
+            .iter()
+            .find(|(_, since)| now.duration_since(*since) >= self.exceeded_for)
+            .map(|(metric, _)| (*metric, usage))
+    }
+    /// Samples resource usage of process, CPU is 0 on first sample
+    pub fn sample(&mut self, pid: u32) -> Option<Usage> {
+        // new process, start over
+        if self.pid != Some(pid) {
+            self.pid = Some(pid);
+            self.cpu_ticks = None;
+            self.since.clear();
+        }
+        let stat = parse_stat(&fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?)?;
+        let now = Instant::now();
+        let cpu = match self.cpu_ticks {
+            Some((ticks, at)) => {
+                let elapsed = now.duration_since(at).as_secs_f64() * clock_ticks() as f64;
+                if elapsed > 0.0 {
+                    (stat.cpu_ticks.saturating_sub(ticks) as f64 * 100.0 / elapsed) as u32
+                } else {
+                    0
+                }
+            }
+            None => 0,
+        };
+        self.cpu_ticks = Some((stat.cpu_ticks, now));
+        let fds = fs::read_dir(format!("/proc/{}/fd", pid))
+            .map(Iterator::count)
+            .unwrap_or(0);
+        Some(Usage {
+            rss: stat.rss_pages * page_size(),
+            cpu,
+            fds,
+            threads: stat.threads,
+        })
+    }
+}
+
+/// Fields of `/proc/<pid>/stat`
+#[derive(Debug, PartialEq, Eq)]
+struct Stat {
+    cpu_ticks: u64,
+    threads: usize,
+    rss_pages: u64,
+}
+
+/// Parses `/proc/<pid>/stat`, process name may contain spaces and parentheses
+fn parse_stat(stat: &str) -> Option<Stat> {
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // fields start at 3, state
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    Some(Stat {
+        cpu_ticks: utime + stime,
+        threads: fields.get(17)?.parse().ok()?,
+        rss_pages: fields.get(21)?.parse().ok()?,
+    })
+}
+
+fn clock_ticks() -> u64 {
+    match sysconf(SysconfVar::CLK_TCK) {
+        Ok(Some(ticks)) if ticks > 0 => ticks as u64,
+        _ => 100,
+    }
+}
+
+fn page_size() -> u64 {
+    match sysconf(SysconfVar::PAGE_SIZE) {
+        Ok(Some(size)) if size > 0 => size as u64,
+        _ => 4096,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn stat() {
+        let stat = "1234 (a (weird) name) S 1 1234 1234 0 -1 4194560 100 0 0 0 \
+                    25 15 0 0 20 0 3 0 1000 10000000 512 18446744073709551615";
+        assert_eq!(
+            parse_stat(stat),
+            Some(Stat {
+                cpu_ticks: 40,
+                threads: 3,
+                rss_pages: 512,
+            })
+        );
+        assert_eq!(parse_stat("1234 (cat) S 1"), None);
+    }
+    #[test]
+    fn thresholds() {
+        let usage = Usage {
+            rss: 2048,
+            cpu: 50,
+            fds: 10,
+            threads: 1,
+        };
+        assert!(Thresholds::default().exceeded(&usage).is_empty());
+        let thresholds = Thresholds {
+            rss: Some(1024),
+            cpu: Some(50),
+            fds: Some(5),
+            threads: None,
+        };
+        assert_eq!(thresholds.exceeded(&usage), vec![Metric::Rss, Metric::Fds]);
+    }
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn sample_self() {
+        let pid = process::id();
+        let mut monitor = UsageMonitor::new(Thresholds::default(), Duration::from_secs(0));
+        let usage = monitor.sample(pid).unwrap();
+        assert!(usage.rss > 0);
+        assert!(usage.fds > 0);
+        assert!(usage.threads > 0);
+        assert_eq!(monitor.check(pid), None);
+        // exceeded right away
+        let mut monitor = UsageMonitor::new(
+            Thresholds {
+                threads: Some(0),
+                ..Thresholds::default()
+            },
+            Duration::from_secs(0),
+        );
+        assert_eq!(
+            monitor.check(pid).map(|(metric, _)| metric),
+            Some(Metric::Threads)
+        );
+        // not for long enough
+        let mut monitor = UsageMonitor::new(
+            Thresholds {
+                threads: Some(0),
+                ..Thresholds::default()
+            },
+            Duration::from_secs(60),
+        );
+        assert_eq!(monitor.check(pid), None);
+        assert_eq!(monitor.check(pid), None);
+    }
+}